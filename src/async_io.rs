@@ -0,0 +1,361 @@
+use std::{
+	collections::HashMap,
+	future::Future,
+	io::{IoSlice, IoSliceMut},
+	os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd},
+	pin::Pin,
+	sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, OnceLock},
+	task::{Context, Poll, Wake, Waker},
+	thread::Thread,
+};
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{
+	c_error::CError,
+	c_result::CResult,
+	file::{read_fd, read_vectored_fd, write_fd, write_vectored_fd, FileDescriptor},
+	pipe::pipe,
+	poll::{Epoll, Events, Interest, Readiness, Trigger},
+	wait::{waitpid_with_options, WaitResult},
+};
+
+// What the reactor has seen of a descriptor since it was last found not ready
+#[derive(Default)]
+struct SourceState {
+	readable: bool,
+	writable: bool,
+	read_waker: Option<Waker>,
+	write_waker: Option<Waker>,
+}
+
+struct Source {
+	token: u64,
+	state: Mutex<SourceState>,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+	Read,
+	Write,
+}
+
+impl Source {
+	fn wake(&self, readiness: Readiness) {
+		let mut state = self.state.lock().unwrap();
+		// Errors and hangups wake both sides, so the next call reports them
+		let failed = readiness.intersects(Readiness::HANGUP | Readiness::ERROR);
+		if failed || readiness.intersects(Readiness::READABLE | Readiness::READ_HANGUP | Readiness::PRIORITY) {
+			state.readable = true;
+			if let Some(waker) = state.read_waker.take() {
+				waker.wake();
+			}
+		}
+		if failed || readiness.contains(Readiness::WRITABLE) {
+			state.writable = true;
+			if let Some(waker) = state.write_waker.take() {
+				waker.wake();
+			}
+		}
+	}
+
+	// Called after an operation failed with Again; Ready means the reactor
+	// saw the descriptor become ready since, so the operation should be
+	// retried, and Pending means the task will be woken when it does
+	fn poll_ready(&self, direction: Direction, cx: &mut Context<'_>) -> Poll<()> {
+		let mut guard = self.state.lock().unwrap();
+		let state = &mut *guard;
+		let (ready, waker) = match direction {
+			Direction::Read => (&mut state.readable, &mut state.read_waker),
+			Direction::Write => (&mut state.writable, &mut state.write_waker),
+		};
+		if *ready {
+			*ready = false;
+			Poll::Ready(())
+		}
+		else {
+			*waker = Some(cx.waker().clone());
+			Poll::Pending
+		}
+	}
+}
+
+// A single epoll instance serving every async descriptor in the process,
+// waited on by its own thread, so any executor can drive the futures
+struct Reactor {
+	epoll: Epoll,
+	sources: Mutex<HashMap<u64, Arc<Source>>>,
+	next_token: AtomicU64,
+}
+
+impl Reactor {
+	fn get() -> &'static Reactor {
+		static REACTOR: OnceLock<Reactor> = OnceLock::new();
+		REACTOR.get_or_init(|| {
+			let reactor = Reactor {
+				epoll: Epoll::new().expect("Failed to create the reactor's epoll instance"),
+				sources: Mutex::new(HashMap::new()),
+				next_token: AtomicU64::new(0),
+			};
+			// Reactor::get blocks in the new thread until this returns
+			std::thread::Builder::new()
+				.name("c_wrapper-reactor".to_string())
+				.spawn(|| Reactor::get().run())
+				.expect("Failed to start the reactor thread");
+			reactor
+		})
+	}
+
+	fn run(&self) -> ! {
+		let mut events = Events::with_capacity(256);
+		loop {
+			self.epoll.wait(&mut events, None).expect("Waiting on the reactor's epoll instance failed");
+			for event in events.iter() {
+				let source = self.sources.lock().unwrap().get(&event.token).cloned();
+				if let Some(source) = source {
+					source.wake(event.readiness);
+				}
+			}
+		}
+	}
+
+	fn register(&self, fd: RawFd) -> CResult<Arc<Source>> {
+		let source = Arc::new(Source {
+			token: self.next_token.fetch_add(1, Ordering::Relaxed),
+			state: Mutex::new(SourceState::default()),
+		});
+		self.sources.lock().unwrap().insert(source.token, source.clone());
+		// Edge-triggered, since readiness is remembered in SourceState
+		let interest = Interest::READABLE | Interest::WRITABLE | Interest::READ_HANGUP;
+		let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+		if let Err(err) = self.epoll.add(fd, interest, Trigger::Edge, source.token) {
+			self.sources.lock().unwrap().remove(&source.token);
+			return Err(err);
+		}
+		Ok(source)
+	}
+
+	fn deregister(&self, fd: RawFd, source: &Source) {
+		let _ = self.epoll.delete(unsafe { BorrowedFd::borrow_raw(fd) });
+		self.sources.lock().unwrap().remove(&source.token);
+	}
+}
+
+fn would_block(err: &std::io::Error) -> bool {
+	err.kind() == std::io::ErrorKind::WouldBlock
+}
+
+// A descriptor switched to non-blocking mode and registered with the
+// reactor, so reads and writes wait by returning Pending instead
+pub struct AsyncFileDescriptor {
+	fd: FileDescriptor,
+	source: Arc<Source>,
+}
+
+impl AsyncFileDescriptor {
+	pub fn new(mut fd: FileDescriptor) -> CResult<Self> {
+		fd.set_nonblocking(true)?;
+		let source = Reactor::get().register(fd.fd)?;
+		Ok(Self {
+			fd,
+			source,
+		})
+	}
+
+	pub fn get_ref(&self) -> &FileDescriptor {
+		&self.fd
+	}
+
+	// Leaves the descriptor in non-blocking mode
+	pub fn into_inner(mut self) -> FileDescriptor {
+		Reactor::get().deregister(self.fd.fd, &self.source);
+		// Drop skips descriptors of -1
+		std::mem::replace(&mut self.fd, unsafe { FileDescriptor::from_unowned(-1) })
+	}
+
+	// Retries operation until it stops failing with Again, waiting for the
+	// reactor in between
+	fn poll_io<T, Operation>(&self, direction: Direction, cx: &mut Context<'_>, mut operation: Operation) -> Poll<std::io::Result<T>>
+	where
+		Operation: FnMut() -> std::io::Result<T>,
+	{
+		loop {
+			match operation() {
+				Err(ref err) if would_block(err) => {},
+				Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+				result => return Poll::Ready(result),
+			}
+			if self.source.poll_ready(direction, cx).is_pending() {
+				return Poll::Pending;
+			}
+		}
+	}
+
+	pub fn poll_read_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+		self.source.poll_ready(Direction::Read, cx)
+	}
+
+	pub fn poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+		self.source.poll_ready(Direction::Write, cx)
+	}
+}
+
+impl Drop for AsyncFileDescriptor {
+	fn drop(&mut self) {
+		if self.fd.fd != -1 {
+			Reactor::get().deregister(self.fd.fd, &self.source);
+		}
+	}
+}
+
+impl AsRawFd for AsyncFileDescriptor {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.fd
+	}
+}
+
+impl AsFd for AsyncFileDescriptor {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.fd.as_fd()
+	}
+}
+
+impl AsyncRead for AsyncFileDescriptor {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+		let fd = self.fd.fd;
+		self.poll_io(Direction::Read, cx, || read_fd(fd, buf))
+	}
+
+	fn poll_read_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &mut [IoSliceMut<'_>]) -> Poll<std::io::Result<usize>> {
+		let fd = self.fd.fd;
+		self.poll_io(Direction::Read, cx, || read_vectored_fd(fd, bufs))
+	}
+}
+
+impl AsyncWrite for AsyncFileDescriptor {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+		let fd = self.fd.fd;
+		self.poll_io(Direction::Write, cx, || write_fd(fd, buf))
+	}
+
+	fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<std::io::Result<usize>> {
+		let fd = self.fd.fd;
+		self.poll_io(Direction::Write, cx, || write_vectored_fd(fd, bufs))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	// The descriptor is closed when dropped
+	fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+}
+
+// Returns the read and write ends
+pub fn async_pipe() -> CResult<(AsyncFileDescriptor, AsyncFileDescriptor)> {
+	let pipe = pipe()?;
+	Ok((AsyncFileDescriptor::new(pipe.read_fd)?, AsyncFileDescriptor::new(pipe.write_fd)?))
+}
+
+// Resolves once the child exits, reaping it; a pidfd becomes readable when
+// its process terminates
+pub struct ChildExit {
+	pid: libc::pid_t,
+	pidfd: AsyncFileDescriptor,
+}
+
+impl ChildExit {
+	pub fn pid(&self) -> libc::pid_t {
+		self.pid
+	}
+}
+
+// pid must be a child of this process; needs Linux 5.3 or later
+pub fn wait_child(pid: libc::pid_t) -> CResult<ChildExit> {
+	let pidfd = match unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0 as libc::c_uint) } {
+		-1 => return Err(CError::new_from_errno()),
+		fd => unsafe { FileDescriptor::from_unowned(fd as libc::c_int) },
+	};
+	Ok(ChildExit {
+		pid,
+		pidfd: AsyncFileDescriptor::new(pidfd)?,
+	})
+}
+
+impl Future for ChildExit {
+	type Output = CResult<WaitResult>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		loop {
+			match waitpid_with_options(self.pid, libc::WNOHANG) {
+				// WNOHANG reports a child that is still running as pid 0
+				Ok(result) if result.pid == 0 => {},
+				Err(CError::Interrupted) => continue,
+				result => return Poll::Ready(result),
+			}
+			if self.pidfd.poll_read_ready(cx).is_pending() {
+				return Poll::Pending;
+			}
+		}
+	}
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+	fn wake(self: Arc<Self>) {
+		self.0.unpark();
+	}
+}
+
+// Runs future to completion on the current thread, for programs that don't
+// otherwise need an executor
+pub fn block_on<F: Future>(future: F) -> F::Output {
+	let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+	let mut cx = Context::from_waker(&waker);
+	let mut future = Box::pin(future);
+	loop {
+		match future.as_mut().poll(&mut cx) {
+			Poll::Ready(output) => return output,
+			Poll::Pending => std::thread::park(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::fork::{fork, ForkResult};
+
+	#[test]
+	fn read_waits_for_the_reactor_to_report_a_pipe_readable() {
+		let (mut reader, writer) = async_pipe().unwrap();
+		// Written only after the read has had to wait
+		let writing = std::thread::spawn(move || {
+			std::thread::sleep(Duration::from_millis(50));
+			let mut writer = writer;
+			block_on(std::future::poll_fn(|cx| Pin::new(&mut writer).poll_write(cx, b"hello"))).unwrap()
+		});
+		let mut buffer = [0u8; 16];
+		let bytes_read = block_on(std::future::poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut buffer))).unwrap();
+		assert_eq!(&buffer[..bytes_read], b"hello");
+		assert_eq!(writing.join().unwrap(), 5);
+	}
+
+	#[test]
+	fn child_exit_resolves_with_the_exit_status() {
+		let pid = match fork().unwrap() {
+			ForkResult::Child => unsafe {
+				libc::usleep(50_000);
+				libc::_exit(3)
+			},
+			ForkResult::Parent(pid) => pid,
+		};
+		let result = block_on(wait_child(pid).unwrap()).unwrap();
+		assert_eq!(result.status.exit_status(), 3);
+	}
+}
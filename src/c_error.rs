@@ -23,9 +23,9 @@ macro_rules! generate_cerror {
             }
         }
 
-        impl Into<libc::c_int> for &CError {
-            fn into(self) -> libc::c_int {
-                match self {
+        impl From<&CError> for libc::c_int {
+            fn from(error: &CError) -> Self {
+                match error {
                     $(
                         CError::$rust_names => libc::$c_names,
                     )+
@@ -34,9 +34,9 @@ macro_rules! generate_cerror {
             }
         }
 
-        impl Into<libc::c_int> for CError {
-            fn into(self) -> libc::c_int {
-                match self {
+        impl From<CError> for libc::c_int {
+            fn from(error: CError) -> Self {
+                match error {
                     $(
                         CError::$rust_names => libc::$c_names,
                     )+
@@ -72,6 +72,14 @@ generate_cerror!(
     Range; to C ERANGE,
     NotFound; to C ENOENT,
     PermissionDenied; to C EACCES,
+    NoSuchProcess; to C ESRCH,
+    NoDeviceOrAddress; to C ENXIO,
+    AlreadyExists; to C EEXIST,
+    NotADirectory; to C ENOTDIR,
+    DirectoryNotEmpty; to C ENOTEMPTY,
+    CrossDevice; to C EXDEV,
+    TooManySymlinks; to C ELOOP,
+    Busy; to C EBUSY,
 );
 
 impl CError {
@@ -7,6 +7,6 @@ pub fn chdir<Path: Into<CString>>(path: Path) -> CResult<()> {
 	match unsafe { libc::chdir(path.as_ptr()) } {
 		0 => Ok(()),
 		-1 => Err(CError::new_from_errno()),
-		bad_return => panic!(format!("Unknown return from access: {}", bad_return)),
+		bad_return => panic!("Unknown return from access: {}", bad_return),
 	}
 }
@@ -0,0 +1,363 @@
+use std::time::{Duration, Instant};
+
+use crate::{c_error::CError, c_result::CResult, file::FileDescriptor, fork::{exit_child_on_error, fork, ForkResult}, pipe::pipe, wait::{waitpid, waitpid_with_options, WaitResult}};
+
+static READ_BUFFER_SIZE: usize = 8192;
+// How often a child that closed its output is checked for having exited
+static WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub fn fork_piped() -> CResult<ForkPipedResult> {
+	let stdin_pipe = pipe()?;
+	let stdout_pipe = pipe()?;
+	let stderr_pipe = pipe()?;
+
+	match fork()? {
+		ForkResult::Child => {
+			exit_child_on_error(
+				FileDescriptor::wrap_stdin(|fd| fd.redirect_from(&stdin_pipe.read_fd))
+					.and_then(|_| FileDescriptor::wrap_stdout(|fd| fd.redirect_from(&stdout_pipe.write_fd)))
+					.and_then(|_| FileDescriptor::wrap_stderr(|fd| fd.redirect_from(&stderr_pipe.write_fd)))
+			);
+			// The original pipe ends are closed when dropped here
+			Ok(ForkPipedResult::Child)
+		},
+		ForkResult::Parent(pid) => Ok(ForkPipedResult::Parent(PipedChild {
+			pid,
+			stdin: Some(stdin_pipe.drop_read()),
+			stdout: Some(stdout_pipe.drop_write()),
+			stderr: Some(stderr_pipe.drop_write()),
+		})),
+	}
+}
+
+pub enum ForkPipedResult {
+	Child,
+	Parent(PipedChild),
+}
+
+pub struct PipedChild {
+	pub pid: libc::pid_t,
+	pub stdin: Option<FileDescriptor>,
+	pub stdout: Option<FileDescriptor>,
+	pub stderr: Option<FileDescriptor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+	Stdout,
+	Stderr,
+}
+
+pub type LineCallback<'a> = Box<dyn FnMut(OutputStream, &[u8]) + 'a>;
+
+#[derive(Default)]
+pub struct CommunicateOptions<'a> {
+	pub stdout_limit: Option<usize>,
+	pub stderr_limit: Option<usize>,
+	// When elapsed, the child is killed with SIGKILL and reaped
+	pub timeout: Option<Duration>,
+	// When set, output is delivered line by line instead of being collected
+	pub line_callback: Option<LineCallback<'a>>,
+}
+
+pub struct CommunicateResult {
+	pub stdout: Vec<u8>,
+	pub stderr: Vec<u8>,
+	pub wait_result: WaitResult,
+	pub stdout_truncated: bool,
+	pub stderr_truncated: bool,
+	pub timed_out: bool,
+}
+
+struct OutputCollector {
+	stream: OutputStream,
+	fd: Option<FileDescriptor>,
+	data: Vec<u8>,
+	limit: Option<usize>,
+	truncated: bool,
+	partial_line: Vec<u8>,
+}
+
+impl OutputCollector {
+	fn new(stream: OutputStream, fd: Option<FileDescriptor>, limit: Option<usize>) -> Self {
+		Self {
+			stream,
+			fd,
+			data: vec![],
+			limit,
+			truncated: false,
+			partial_line: vec![],
+		}
+	}
+
+	fn push(&mut self, bytes: &[u8], line_callback: &mut Option<LineCallback>) {
+		if let Some(callback) = line_callback {
+			self.partial_line.extend_from_slice(bytes);
+			while let Some(newline) = self.partial_line.iter().position(|b| *b == b'\n') {
+				let line: Vec<u8> = self.partial_line.drain(..=newline).collect();
+				callback(self.stream, &line);
+			}
+			return;
+		}
+
+		let accepted = match self.limit {
+			Some(limit) => bytes.len().min(limit.saturating_sub(self.data.len())),
+			None => bytes.len(),
+		};
+		if accepted < bytes.len() {
+			self.truncated = true;
+		}
+		self.data.extend_from_slice(&bytes[..accepted]);
+	}
+
+	fn finish(&mut self, line_callback: &mut Option<LineCallback>) {
+		self.fd = None;
+		if let Some(callback) = line_callback {
+			if !self.partial_line.is_empty() {
+				callback(self.stream, &self.partial_line);
+				self.partial_line.clear();
+			}
+		}
+	}
+
+	// Reads until the pipe would block, closing it once EOF is reached
+	fn drain(&mut self, line_callback: &mut Option<LineCallback>) -> CResult<()> {
+		loop {
+			let fd = match self.fd.as_mut() {
+				Some(fd) => fd,
+				None => return Ok(()),
+			};
+			match fd.read_bytes(READ_BUFFER_SIZE) {
+				Ok(bytes) if bytes.is_empty() => {
+					self.finish(line_callback);
+					return Ok(());
+				},
+				Ok(bytes) => self.push(&bytes, line_callback),
+				Err(CError::Again) | Err(CError::WouldBlock) => return Ok(()),
+				Err(CError::Interrupted) => continue,
+				Err(err) => return Err(err),
+			}
+		}
+	}
+}
+
+fn wait_blocking(pid: libc::pid_t) -> CResult<WaitResult> {
+	loop {
+		match waitpid(pid) {
+			Err(CError::Interrupted) => continue,
+			result => return result,
+		}
+	}
+}
+
+// A child can close its output and keep running, so the deadline still
+// applies while waiting for it to exit; returns whether it had to be killed
+fn wait_until(pid: libc::pid_t, deadline: Instant) -> CResult<(WaitResult, bool)> {
+	loop {
+		match waitpid_with_options(pid, libc::WNOHANG) {
+			// WNOHANG reports a child that is still running as pid 0
+			Ok(result) if result.pid == 0 => {},
+			Err(CError::Interrupted) => continue,
+			result => return Ok((result?, false)),
+		}
+		let now = Instant::now();
+		if now >= deadline {
+			unsafe { libc::kill(pid, libc::SIGKILL) };
+			return Ok((wait_blocking(pid)?, true));
+		}
+		std::thread::sleep((deadline - now).min(WAIT_POLL_INTERVAL));
+	}
+}
+
+impl PipedChild {
+	pub fn communicate(self, input: &[u8]) -> CResult<CommunicateResult> {
+		self.communicate_with_options(input, CommunicateOptions::default())
+	}
+
+	pub fn communicate_with_options(self, input: &[u8], options: CommunicateOptions) -> CResult<CommunicateResult> {
+		let CommunicateOptions { stdout_limit, stderr_limit, timeout, mut line_callback } = options;
+
+		let mut stdin = self.stdin;
+		let mut stdout = OutputCollector::new(OutputStream::Stdout, self.stdout, stdout_limit);
+		let mut stderr = OutputCollector::new(OutputStream::Stderr, self.stderr, stderr_limit);
+
+		if input.is_empty() {
+			stdin = None;
+		}
+		if let Some(fd) = stdin.as_mut() {
+			fd.set_nonblocking(true)?;
+		}
+		for fd in [stdout.fd.as_mut(), stderr.fd.as_mut()].iter_mut().flatten() {
+			fd.set_nonblocking(true)?;
+		}
+
+		let deadline = timeout.map(|timeout| Instant::now() + timeout);
+		let mut written = 0;
+		let mut timed_out = false;
+
+		while stdin.is_some() || stdout.fd.is_some() || stderr.fd.is_some() {
+			let mut poll_fds = vec![];
+			if let Some(fd) = &stdin {
+				poll_fds.push(libc::pollfd { fd: fd.fd, events: libc::POLLOUT, revents: 0 });
+			}
+			for collector in [&stdout, &stderr].iter() {
+				if let Some(fd) = &collector.fd {
+					poll_fds.push(libc::pollfd { fd: fd.fd, events: libc::POLLIN, revents: 0 });
+				}
+			}
+
+			let poll_timeout = match deadline {
+				Some(deadline) => {
+					let now = Instant::now();
+					if now >= deadline {
+						0
+					}
+					else {
+						// Round up so that we don't spin right before the deadline
+						let remaining = (deadline - now).as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+						remaining.saturating_add(1)
+					}
+				},
+				None => -1,
+			};
+
+			if unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, poll_timeout) } == -1 {
+				match CError::new_from_errno() {
+					CError::Interrupted => continue,
+					err => return Err(err),
+				}
+			}
+			// Checked on every wakeup, since a child that keeps writing keeps
+			// poll from ever timing out
+			if let Some(deadline) = deadline {
+				if Instant::now() >= deadline {
+					timed_out = true;
+					unsafe { libc::kill(self.pid, libc::SIGKILL) };
+					break;
+				}
+			}
+
+			let ready = |fd: &Option<FileDescriptor>| -> bool {
+				match fd {
+					Some(fd) => poll_fds.iter().any(|poll_fd| poll_fd.fd == fd.fd && poll_fd.revents != 0),
+					None => false,
+				}
+			};
+			let stdin_ready = ready(&stdin);
+			let stdout_ready = ready(&stdout.fd);
+			let stderr_ready = ready(&stderr.fd);
+
+			if stdin_ready {
+				if let Some(fd) = stdin.as_mut() {
+					match fd.write_slice(&input[written..]) {
+						Ok(bytes_written) => written += bytes_written,
+						Err(CError::Again) | Err(CError::WouldBlock) | Err(CError::Interrupted) => {},
+						// The child closed its stdin; the rest of the input is discarded
+						Err(CError::BrokenPipe) => written = input.len(),
+						Err(err) => return Err(err),
+					}
+				}
+				if written == input.len() {
+					stdin = None;
+				}
+			}
+			if stdout_ready {
+				stdout.drain(&mut line_callback)?;
+			}
+			if stderr_ready {
+				stderr.drain(&mut line_callback)?;
+			}
+		}
+
+		drop(stdin);
+		stdout.finish(&mut line_callback);
+		stderr.finish(&mut line_callback);
+
+		let wait_result = match deadline {
+			Some(deadline) if !timed_out => {
+				let (wait_result, killed) = wait_until(self.pid, deadline)?;
+				timed_out = killed;
+				wait_result
+			},
+			_ => wait_blocking(self.pid)?,
+		};
+
+		Ok(CommunicateResult {
+			stdout: stdout.data,
+			stderr: stderr.data,
+			wait_result,
+			stdout_truncated: stdout.truncated,
+			stderr_truncated: stderr.truncated,
+			timed_out,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Runs child in a forked process wired up to pipes; child must not return
+	fn spawn(child: fn() -> !) -> PipedChild {
+		match fork_piped().unwrap() {
+			ForkPipedResult::Child => child(),
+			ForkPipedResult::Parent(piped_child) => piped_child,
+		}
+	}
+
+	fn with_timeout(timeout: Duration) -> CommunicateOptions<'static> {
+		CommunicateOptions {
+			timeout: Some(timeout),
+			..CommunicateOptions::default()
+		}
+	}
+
+	#[test]
+	fn collects_output_and_exit_status() {
+		let child = spawn(|| unsafe {
+			let mut buffer = [0u8; 16];
+			let bytes_read = libc::read(0, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len());
+			libc::write(1, buffer.as_ptr() as *const libc::c_void, bytes_read as usize);
+			libc::write(2, b"err".as_ptr() as *const libc::c_void, 3);
+			libc::_exit(3)
+		});
+		let result = child.communicate(b"input").unwrap();
+		assert_eq!(result.stdout, b"input");
+		assert_eq!(result.stderr, b"err");
+		assert!(result.wait_result.status.exited_normally());
+		assert_eq!(result.wait_result.status.exit_status(), 3);
+		assert!(!result.timed_out);
+	}
+
+	#[test]
+	fn timeout_kills_a_child_that_keeps_writing() {
+		let child = spawn(|| loop {
+			unsafe { libc::write(1, b"y\n".as_ptr() as *const libc::c_void, 2) };
+		});
+		let start = Instant::now();
+		let options = CommunicateOptions {
+			stdout_limit: Some(1024),
+			..with_timeout(Duration::from_millis(200))
+		};
+		let result = child.communicate_with_options(&[], options).unwrap();
+		assert!(result.timed_out);
+		assert!(result.stdout_truncated);
+		assert_eq!(result.wait_result.status.terminating_signal(), libc::SIGKILL);
+		assert!(start.elapsed() < Duration::from_secs(5));
+	}
+
+	#[test]
+	fn timeout_kills_a_child_that_closed_its_output() {
+		let child = spawn(|| unsafe {
+			libc::close(1);
+			libc::close(2);
+			libc::sleep(60);
+			libc::_exit(0)
+		});
+		let start = Instant::now();
+		let result = child.communicate_with_options(&[], with_timeout(Duration::from_millis(200))).unwrap();
+		assert!(result.timed_out);
+		assert_eq!(result.wait_result.status.terminating_signal(), libc::SIGKILL);
+		assert!(start.elapsed() < Duration::from_secs(5));
+	}
+}
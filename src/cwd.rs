@@ -6,12 +6,11 @@ use crate::c_error::CError;
 static INITIAL_BUFFER_SIZE: usize = 100;
 
 pub fn getcwd() -> CResult<String> {
-	let mut buffer = Vec::<libc::c_char>::new();
-	buffer.resize(INITIAL_BUFFER_SIZE, 0);
+	let mut buffer = vec![0 as libc::c_char; INITIAL_BUFFER_SIZE];
 
 	loop {
 		let ptr = unsafe { libc::getcwd(buffer.as_mut_ptr(), buffer.len()) };
-		if ptr == std::ptr::null_mut() {
+		if ptr.is_null() {
 			match CError::new_from_errno() {
 				CError::Range => buffer.resize(buffer.len() * 2, 0),
 				other => break Err(other),
@@ -26,7 +25,7 @@ pub fn getcwd() -> CResult<String> {
 
 pub fn getcwd_tilde() -> CResult<String> {
 	getcwd().map(|wd| -> String {
-		if let Some(home) = std::env::var("HOME").ok() {
+		if let Ok(home) = std::env::var("HOME") {
 			if wd.starts_with(&home) {
 				"~".to_string() + &wd[home.len()..]
 			}
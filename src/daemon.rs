@@ -0,0 +1,194 @@
+use std::{ffi::CString, mem::size_of};
+
+use crate::{c_error::CError, c_result::CResult, chdir::chdir, file::{constants::STDERR_FILENO, lock::{LockKind, LockRange}, open::{open_with_flags, open_with_mode, flags::{O_APPEND, O_CLOEXEC, O_CREAT, O_RDWR, O_WRONLY}}, FileDescriptor}, fork::{fork, ForkResult}, pipe::pipe, wait::waitpid};
+
+pub enum DaemonStdio {
+	DevNull,
+	// Opened for appending, and created if missing
+	File(String),
+}
+
+pub struct DaemonizeOptions {
+	pub working_directory: String,
+	pub umask: libc::mode_t,
+	pub stdin: DaemonStdio,
+	pub stdout: DaemonStdio,
+	pub stderr: DaemonStdio,
+	pub pidfile: Option<String>,
+	pub close_inherited_fds: bool,
+}
+
+impl Default for DaemonizeOptions {
+	fn default() -> Self {
+		Self {
+			working_directory: "/".to_string(),
+			umask: 0o027,
+			stdin: DaemonStdio::DevNull,
+			stdout: DaemonStdio::DevNull,
+			stderr: DaemonStdio::DevNull,
+			pidfile: None,
+			close_inherited_fds: true,
+		}
+	}
+}
+
+pub enum DaemonizeResult {
+	Daemon(DaemonHandle),
+	Parent(libc::pid_t),
+}
+
+pub struct DaemonHandle {
+	// Keeping the pidfile open keeps it locked
+	pidfile: Option<FileDescriptor>,
+	pidfile_path: Option<String>,
+}
+
+impl DaemonHandle {
+	pub fn pid(&self) -> libc::pid_t {
+		unsafe { libc::getpid() }
+	}
+
+	pub fn remove_pidfile(mut self) -> CResult<()> {
+		if let Some(path) = self.pidfile_path.take() {
+			let path = CString::new(path).map_err(|_| CError::Invalid)?;
+			if unsafe { libc::unlink(path.as_ptr()) } == -1 {
+				return Err(CError::new_from_errno());
+			}
+		}
+		self.pidfile = None;
+		Ok(())
+	}
+}
+
+// In the original process, returns the daemon's pid once it has started, or
+// the error that stopped it from starting. An already locked pidfile is
+// reported as CError::Again.
+pub fn daemonize(options: DaemonizeOptions) -> CResult<DaemonizeResult> {
+	let status_pipe = pipe()?;
+
+	match fork()? {
+		ForkResult::Parent(intermediate_pid) => {
+			let mut status_fd = status_pipe.drop_write();
+			let _ = waitpid(intermediate_pid);
+			let status = status_fd.read_bytes(size_of::<libc::c_int>())?;
+			if status.len() != size_of::<libc::c_int>() {
+				// Everything exited without reporting back
+				return Err(CError::Child);
+			}
+			let mut status_bytes = [0u8; size_of::<libc::c_int>()];
+			status_bytes.copy_from_slice(&status);
+			match libc::c_int::from_ne_bytes(status_bytes) {
+				pid if pid > 0 => Ok(DaemonizeResult::Parent(pid)),
+				errno => Err(CError::from(-errno)),
+			}
+		},
+		ForkResult::Child => {
+			let mut status_fd = status_pipe.drop_read();
+			if unsafe { libc::setsid() } == -1 {
+				report_error(&mut status_fd, CError::new_from_errno());
+			}
+			match fork() {
+				Err(err) => report_error(&mut status_fd, err),
+				Ok(ForkResult::Parent(_)) => unsafe { libc::_exit(0) },
+				Ok(ForkResult::Child) => {},
+			}
+			match setup_daemon(&options, &status_fd) {
+				Ok(handle) => {
+					let _ = status_fd.write_any(unsafe { libc::getpid() });
+					Ok(DaemonizeResult::Daemon(handle))
+				},
+				Err(err) => report_error(&mut status_fd, err),
+			}
+		},
+	}
+}
+
+fn report_error(status_fd: &mut FileDescriptor, err: CError) -> ! {
+	let errno: libc::c_int = err.into();
+	let _ = status_fd.write_any(-errno);
+	unsafe { libc::_exit(1) }
+}
+
+fn setup_daemon(options: &DaemonizeOptions, status_fd: &FileDescriptor) -> CResult<DaemonHandle> {
+	chdir(CString::new(options.working_directory.as_str()).map_err(|_| CError::Invalid)?)?;
+	unsafe { libc::umask(options.umask) };
+
+	if options.close_inherited_fds {
+		close_fds_except(status_fd.fd);
+	}
+
+	let pidfile = match &options.pidfile {
+		Some(path) => Some(lock_pidfile(path)?),
+		None => None,
+	};
+
+	let stdin = open_stdio(&options.stdin, true)?;
+	let stdout = open_stdio(&options.stdout, false)?;
+	let stderr = open_stdio(&options.stderr, false)?;
+	FileDescriptor::wrap_stdin(|fd| fd.redirect_from(&stdin))?;
+	FileDescriptor::wrap_stdout(|fd| fd.redirect_from(&stdout))?;
+	FileDescriptor::wrap_stderr(|fd| fd.redirect_from(&stderr))?;
+
+	Ok(DaemonHandle {
+		pidfile,
+		pidfile_path: options.pidfile.clone(),
+	})
+}
+
+fn open_stdio(stdio: &DaemonStdio, input: bool) -> CResult<FileDescriptor> {
+	match stdio {
+		DaemonStdio::DevNull => open_with_flags(CString::new("/dev/null").unwrap(), O_RDWR | O_CLOEXEC),
+		DaemonStdio::File(path) => {
+			let path = CString::new(path.as_str()).map_err(|_| CError::Invalid)?;
+			if input {
+				open_with_flags(path, O_CLOEXEC)
+			}
+			else {
+				open_with_mode(path, O_WRONLY | O_CREAT | O_APPEND | O_CLOEXEC, 0o640)
+			}
+		},
+	}
+}
+
+fn lock_pidfile(path: &str) -> CResult<FileDescriptor> {
+	let path = CString::new(path).map_err(|_| CError::Invalid)?;
+	let mut fd = open_with_mode(path, O_RDWR | O_CREAT | O_CLOEXEC, 0o644)?;
+
+	match fd.try_lock(LockKind::Exclusive, LockRange::whole_file())? {
+		// The lock is held for as long as the pidfile stays open
+		Some(guard) => std::mem::forget(guard),
+		None => return Err(CError::Again),
+	}
+
+	if unsafe { libc::ftruncate(fd.fd, 0) } == -1 {
+		return Err(CError::new_from_errno());
+	}
+	fd.write(format!("{}\n", unsafe { libc::getpid() }))?;
+	Ok(fd)
+}
+
+// Closes every descriptor above stderr except keep
+fn close_fds_except(keep: libc::c_int) {
+	let first = STDERR_FILENO + 1;
+	#[cfg(target_os = "linux")]
+	{
+		let close_range = |first: libc::c_int, last: libc::c_uint| unsafe {
+			libc::syscall(libc::SYS_close_range, first as libc::c_uint, last, 0 as libc::c_uint)
+		};
+		let below_closed = keep <= first || close_range(first, (keep - 1) as libc::c_uint) == 0;
+		if below_closed && close_range(keep + 1, libc::c_uint::MAX) == 0 {
+			return;
+		}
+		// Kernels before 5.9 lack close_range, so fall through to closing one by one
+	}
+
+	let max_fd = match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+		-1 => 1024,
+		max => max as libc::c_int,
+	};
+	for fd in first..max_fd {
+		if fd != keep {
+			unsafe { libc::close(fd) };
+		}
+	}
+}
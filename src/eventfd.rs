@@ -0,0 +1,133 @@
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult, file::FileDescriptor};
+
+bitflags! {
+	pub struct EventFdFlags: libc::c_int {
+		const CLOEXEC = libc::EFD_CLOEXEC;
+		const NONBLOCK = libc::EFD_NONBLOCK;
+		// take returns 1 and decrements the counter, instead of returning
+		// and clearing all of it
+		const SEMAPHORE = libc::EFD_SEMAPHORE;
+	}
+}
+
+// A counter in the kernel that is readable while it isn't zero, for waking
+// up a thread or process blocked on it or polling it
+pub struct EventFd {
+	fd: FileDescriptor,
+}
+
+impl EventFd {
+	pub fn new(initial_value: u32, flags: EventFdFlags) -> CResult<Self> {
+		match unsafe { libc::eventfd(initial_value, flags.bits()) } {
+			-1 => Err(CError::new_from_errno()),
+			fd => Ok(Self {
+				fd: unsafe { FileDescriptor::from_unowned(fd) },
+			}),
+		}
+	}
+
+	pub fn counter(initial_value: u32) -> CResult<Self> {
+		Self::new(initial_value, EventFdFlags::CLOEXEC)
+	}
+
+	pub fn semaphore(initial_value: u32) -> CResult<Self> {
+		Self::new(initial_value, EventFdFlags::CLOEXEC | EventFdFlags::SEMAPHORE)
+	}
+
+	pub fn fd(&self) -> &FileDescriptor {
+		&self.fd
+	}
+
+	pub fn into_fd(self) -> FileDescriptor {
+		self.fd
+	}
+
+	pub fn set_nonblocking(&mut self, nonblocking: bool) -> CResult<()> {
+		self.fd.set_nonblocking(nonblocking)
+	}
+
+	// Adds value to the counter; blocks, or fails with Again when
+	// non-blocking, if that would overflow it
+	pub fn notify(&self, value: u64) -> CResult<()> {
+		let bytes = value.to_ne_bytes();
+		loop {
+			match unsafe { libc::write(self.fd.fd, bytes.as_ptr() as *const libc::c_void, bytes.len()) } {
+				-1 => match CError::new_from_errno() {
+					CError::Interrupted => continue,
+					err => return Err(err),
+				},
+				_ => return Ok(()),
+			}
+		}
+	}
+
+	// Blocks while the counter is zero, or fails with Again when non-blocking
+	pub fn take(&self) -> CResult<u64> {
+		let mut bytes = [0u8; 8];
+		loop {
+			match unsafe { libc::read(self.fd.fd, bytes.as_mut_ptr() as *mut libc::c_void, bytes.len()) } {
+				-1 => match CError::new_from_errno() {
+					CError::Interrupted => continue,
+					err => return Err(err),
+				},
+				_ => return Ok(u64::from_ne_bytes(bytes)),
+			}
+		}
+	}
+
+	// Returns None instead of failing with Again, for non-blocking use
+	pub fn try_take(&self) -> CResult<Option<u64>> {
+		match self.take() {
+			Ok(value) => Ok(Some(value)),
+			Err(CError::Again) | Err(CError::WouldBlock) => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+}
+
+impl AsRawFd for EventFd {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.fd
+	}
+}
+
+impl AsFd for EventFd {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.fd.as_fd()
+	}
+}
+
+impl From<EventFd> for FileDescriptor {
+	fn from(eventfd: EventFd) -> Self {
+		eventfd.fd
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn a_counter_is_read_whole_and_reset() {
+		let mut counter = EventFd::counter(2).unwrap();
+		counter.set_nonblocking(true).unwrap();
+		counter.notify(3).unwrap();
+		assert_eq!(counter.take().unwrap(), 5);
+		assert_eq!(counter.try_take().unwrap(), None);
+	}
+
+	#[test]
+	fn a_semaphore_is_read_one_at_a_time() {
+		let mut semaphore = EventFd::semaphore(2).unwrap();
+		semaphore.set_nonblocking(true).unwrap();
+		semaphore.notify(1).unwrap();
+		for _ in 0..3 {
+			assert_eq!(semaphore.take().unwrap(), 1);
+		}
+		assert_eq!(semaphore.try_take().unwrap(), None);
+	}
+}
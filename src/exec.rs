@@ -4,7 +4,7 @@ use crate::{c_error::CError, c_result::CResult};
 
 pub fn exec(pathname: &str, argv: &[String]) -> CResult<()> {
 	let pathname = CString::new(pathname).unwrap();	// A Rust String will never error
-	let mut argv: Vec<_> = argv.into_iter().map(
+	let mut argv: Vec<_> = argv.iter().map(
 		|arg| CString::new(arg as &str).unwrap()
 	).map(
 		|arg| arg.into_raw() as *const libc::c_char	// Transfer ownership to C
@@ -25,7 +25,7 @@ pub fn exec(pathname: &str, argv: &[String]) -> CResult<()> {
 
 pub fn exece(pathname: &str, argv: &[String], env: &[String]) -> CResult<()> {
 	let pathname = CString::new(pathname).unwrap();	// A Rust String will never error
-	let mut argv: Vec<_> = argv.into_iter().map(
+	let mut argv: Vec<_> = argv.iter().map(
 		|arg| CString::new(arg as &str).unwrap()
 	).map(
 		|arg| arg.into_raw() as *const libc::c_char	// Transfer ownership to C
@@ -35,7 +35,7 @@ pub fn exece(pathname: &str, argv: &[String], env: &[String]) -> CResult<()> {
 
 	let argv_ptr = argv.as_ptr();
 
-	let mut env: Vec<_> = env.into_iter().map(
+	let mut env: Vec<_> = env.iter().map(
 		|arg| CString::new(arg as &str).unwrap()
 	).map(
 		|arg| arg.into_raw() as *const libc::c_char	// Transfer ownership to C
@@ -56,7 +56,7 @@ pub fn exece(pathname: &str, argv: &[String], env: &[String]) -> CResult<()> {
 
 pub fn execp(file: &str, argv: &[String]) -> CResult<()> {
 	let file = CString::new(file).unwrap();	// A Rust String will never error
-	let mut argv: Vec<_> = argv.into_iter().map(
+	let mut argv: Vec<_> = argv.iter().map(
 		|arg| CString::new(arg as &str).unwrap()
 	).map(
 		|arg| arg.into_raw() as *const libc::c_char	// Transfer ownership to C
@@ -78,7 +78,7 @@ pub fn execp(file: &str, argv: &[String]) -> CResult<()> {
 #[cfg(target_os = "linux")]
 pub fn execpe(file: &str, argv: &[String], env: &[String]) -> CResult<()> {
 	let file = CString::new(file).unwrap();	// A Rust String will never error
-	let mut argv: Vec<_> = argv.into_iter().map(
+	let mut argv: Vec<_> = argv.iter().map(
 		|arg| CString::new(arg as &str).unwrap()
 	).map(
 		|arg| arg.into_raw() as *const libc::c_char	// Transfer ownership to C
@@ -88,7 +88,7 @@ pub fn execpe(file: &str, argv: &[String], env: &[String]) -> CResult<()> {
 
 	let argv_ptr = argv.as_ptr();
 
-	let mut env: Vec<_> = env.into_iter().map(
+	let mut env: Vec<_> = env.iter().map(
 		|arg| CString::new(arg as &str).unwrap()
 	).map(
 		|arg| arg.into_raw() as *const libc::c_char	// Transfer ownership to C
@@ -0,0 +1,344 @@
+use std::{ffi::CString, time::{Duration, Instant}};
+
+use regex::bytes::Regex;
+
+use crate::{c_error::CError, c_result::CResult, file::FileDescriptor, pty::{fork_pty, ForkPtyResult, WindowSize}, wait::{waitpid, waitpid_with_options, WaitResult}};
+
+static DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+static READ_BUFFER_SIZE: usize = 4096;
+
+pub enum Pattern {
+	Substring(Vec<u8>),
+	Regex(Regex),
+}
+
+impl Pattern {
+	pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+		Ok(Self::Regex(Regex::new(pattern)?))
+	}
+
+	// Returns the start and end of the first match
+	fn find(&self, haystack: &[u8]) -> Option<(usize, usize)> {
+		match self {
+			Pattern::Substring(needle) if needle.is_empty() => Some((0, 0)),
+			Pattern::Substring(needle) => haystack.windows(needle.len())
+				.position(|window| window == needle.as_slice())
+				.map(|start| (start, start + needle.len())),
+			Pattern::Regex(regex) => regex.find(haystack).map(|found| (found.start(), found.end())),
+		}
+	}
+}
+
+impl From<&str> for Pattern {
+	fn from(substring: &str) -> Self {
+		Self::Substring(substring.as_bytes().to_vec())
+	}
+}
+
+impl From<&[u8]> for Pattern {
+	fn from(substring: &[u8]) -> Self {
+		Self::Substring(substring.to_vec())
+	}
+}
+
+impl From<Regex> for Pattern {
+	fn from(regex: Regex) -> Self {
+		Self::Regex(regex)
+	}
+}
+
+pub struct Match {
+	// Output between the previous match and this one
+	pub before: Vec<u8>,
+	pub matched: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum ExpectError {
+	Timeout {
+		seen: Vec<u8>,
+	},
+	Eof {
+		seen: Vec<u8>,
+	},
+	Os(CError),
+}
+
+impl From<CError> for ExpectError {
+	fn from(err: CError) -> Self {
+		Self::Os(err)
+	}
+}
+
+impl std::fmt::Display for ExpectError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ExpectError::Timeout { seen } => write!(f, "timed out waiting for pattern; output so far: {:?}", String::from_utf8_lossy(seen)),
+			ExpectError::Eof { seen } => write!(f, "program exited before pattern appeared; output so far: {:?}", String::from_utf8_lossy(seen)),
+			ExpectError::Os(err) => write!(f, "{}", err),
+		}
+	}
+}
+
+impl std::error::Error for ExpectError {}
+
+pub struct Session {
+	pid: libc::pid_t,
+	master: FileDescriptor,
+	// Output not yet consumed by a match
+	pending: Vec<u8>,
+	transcript: Vec<u8>,
+	timeout: Duration,
+	eof: bool,
+	reaped: bool,
+}
+
+// Runs file (searched in PATH) with argv on a new pseudo-terminal
+pub fn spawn(file: &str, argv: &[String]) -> CResult<Session> {
+	spawn_with_window_size(file, argv, WindowSize { rows: 24, columns: 80, ..Default::default() })
+}
+
+pub fn spawn_with_window_size(file: &str, argv: &[String], window_size: WindowSize) -> CResult<Session> {
+	// Built before forking, so the child doesn't allocate, which isn't safe
+	// after fork in a multi-threaded program; NULs can't be passed to exec
+	let file = CString::new(file).map_err(|_| CError::Invalid)?;
+	let argv = argv.iter().map(|arg| CString::new(arg.as_str())).collect::<Result<Vec<CString>, _>>().map_err(|_| CError::Invalid)?;
+	let mut argv_pointers: Vec<*const libc::c_char> = argv.iter().map(|arg| arg.as_ptr()).collect();
+	argv_pointers.push(std::ptr::null());
+
+	// fork_pty exits the child itself if it can't set up the terminal, so
+	// the child never returns from here: it either execs or exits with 127
+	match fork_pty(Some(window_size))? {
+		ForkPtyResult::Child => unsafe {
+			libc::execvp(file.as_ptr(), argv_pointers.as_ptr());
+			libc::_exit(127)
+		},
+		ForkPtyResult::Parent { pid, mut master } => {
+			master.set_nonblocking(true)?;
+			Ok(Session {
+				pid,
+				master,
+				pending: vec![],
+				transcript: vec![],
+				timeout: DEFAULT_TIMEOUT,
+				eof: false,
+				reaped: false,
+			})
+		},
+	}
+}
+
+impl Session {
+	pub fn pid(&self) -> libc::pid_t {
+		self.pid
+	}
+
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		self.timeout = timeout;
+	}
+
+	// Everything the program has printed so far, including consumed output
+	pub fn transcript(&self) -> &[u8] {
+		&self.transcript
+	}
+
+	pub fn master(&self) -> &FileDescriptor {
+		&self.master
+	}
+
+	pub fn send(&mut self, mut data: &[u8]) -> CResult<()> {
+		while !data.is_empty() {
+			match self.master.write_slice(data) {
+				Ok(written) => data = &data[written..],
+				Err(CError::Again) | Err(CError::WouldBlock) => {
+					wait_for(&self.master, libc::POLLOUT, None)?;
+				},
+				Err(CError::Interrupted) => {},
+				Err(err) => return Err(err),
+			}
+		}
+		Ok(())
+	}
+
+	pub fn send_line(&mut self, line: &str) -> CResult<()> {
+		self.send(line.as_bytes())?;
+		self.send(b"\r")
+	}
+
+	// Sends a control character, such as 'c' for Ctrl-C
+	pub fn send_control(&mut self, c: char) -> CResult<()> {
+		let byte = (c.to_ascii_uppercase() as u8) & 0x1f;
+		self.send(&[byte])
+	}
+
+	pub fn expect<P: Into<Pattern>>(&mut self, pattern: P) -> Result<Match, ExpectError> {
+		let pattern = pattern.into();
+		let deadline = Instant::now() + self.timeout;
+
+		loop {
+			if let Some((start, end)) = pattern.find(&self.pending) {
+				let matched = self.pending[start..end].to_vec();
+				let mut before: Vec<u8> = self.pending.drain(..end).collect();
+				before.truncate(start);
+				return Ok(Match {
+					before,
+					matched,
+				});
+			}
+			if self.eof {
+				return Err(ExpectError::Eof { seen: self.pending.clone() });
+			}
+			let now = Instant::now();
+			if now >= deadline {
+				return Err(ExpectError::Timeout { seen: self.pending.clone() });
+			}
+			self.fill(deadline - now)?;
+		}
+	}
+
+	// Waits for the program to close the terminal and returns the remaining output
+	pub fn expect_eof(&mut self) -> Result<Vec<u8>, ExpectError> {
+		let deadline = Instant::now() + self.timeout;
+		while !self.eof {
+			let now = Instant::now();
+			if now >= deadline {
+				return Err(ExpectError::Timeout { seen: self.pending.clone() });
+			}
+			self.fill(deadline - now)?;
+		}
+		Ok(std::mem::take(&mut self.pending))
+	}
+
+	pub fn kill(&self, signal: libc::c_int) -> CResult<()> {
+		match unsafe { libc::kill(self.pid, signal) } {
+			0 => Ok(()),
+			_ => Err(CError::new_from_errno()),
+		}
+	}
+
+	pub fn wait(mut self) -> CResult<WaitResult> {
+		// Closing the master hangs up the terminal; Drop skips descriptors of -1
+		drop(std::mem::replace(&mut self.master, unsafe { FileDescriptor::from_unowned(-1) }));
+		self.reaped = true;
+		waitpid(self.pid)
+	}
+
+	// Reads whatever output arrives within timeout
+	fn fill(&mut self, timeout: Duration) -> CResult<()> {
+		if !wait_for(&self.master, libc::POLLIN, Some(timeout))? {
+			return Ok(());
+		}
+		loop {
+			match self.master.read_bytes(READ_BUFFER_SIZE) {
+				Ok(bytes) if bytes.is_empty() => {
+					self.eof = true;
+					return Ok(());
+				},
+				Ok(bytes) => {
+					self.pending.extend_from_slice(&bytes);
+					self.transcript.extend_from_slice(&bytes);
+				},
+				Err(CError::Again) | Err(CError::WouldBlock) => return Ok(()),
+				Err(CError::Interrupted) => {},
+				// Linux reports a closed slave side as EIO on the master
+				Err(CError::IO) => {
+					self.eof = true;
+					return Ok(());
+				},
+				Err(err) => return Err(err),
+			}
+		}
+	}
+}
+
+// A session dropped without wait would leave a zombie behind, so the program
+// is killed, unless it already exited, and reaped
+impl Drop for Session {
+	fn drop(&mut self) {
+		if self.reaped {
+			return;
+		}
+		match waitpid_with_options(self.pid, libc::WNOHANG) {
+			// WNOHANG reports a child that is still running as pid 0
+			Ok(result) if result.pid == 0 => {
+				let _ = self.kill(libc::SIGKILL);
+				let _ = waitpid(self.pid);
+			},
+			_ => {},
+		}
+	}
+}
+
+// Rounded up so that we don't spin right before the deadline, and capped at
+// what poll can take; -1 waits forever
+fn poll_timeout(timeout: Option<Duration>) -> libc::c_int {
+	match timeout {
+		Some(timeout) => (timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int).saturating_add(1),
+		None => -1,
+	}
+}
+
+fn wait_for(fd: &FileDescriptor, events: libc::c_short, timeout: Option<Duration>) -> CResult<bool> {
+	let mut poll_fd = libc::pollfd { fd: fd.fd, events, revents: 0 };
+	let timeout = poll_timeout(timeout);
+	loop {
+		match unsafe { libc::poll(&mut poll_fd, 1, timeout) } {
+			-1 => match CError::new_from_errno() {
+				CError::Interrupted => continue,
+				err => return Err(err),
+			},
+			0 => return Ok(false),
+			_ => return Ok(true),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn shell(script: &str) -> Session {
+		spawn("sh", &["sh".to_string(), "-c".to_string(), script.to_string()]).unwrap()
+	}
+
+	#[test]
+	fn long_timeouts_are_capped_instead_of_wrapping() {
+		assert_eq!(poll_timeout(Some(Duration::from_secs(30 * 24 * 60 * 60))), libc::c_int::MAX);
+		assert_eq!(poll_timeout(Some(Duration::from_millis(5))), 6);
+		assert_eq!(poll_timeout(None), -1);
+	}
+
+	#[test]
+	fn expect_matches_output_and_wait_reaps() {
+		let mut session = shell("echo ready; read line; echo got $line; exit 4");
+		session.expect("ready").unwrap();
+		session.send_line("input").unwrap();
+		session.expect("got input").unwrap();
+		// Closing the terminal before the shell exits would hang it up
+		session.expect_eof().unwrap();
+		let result = session.wait().unwrap();
+		assert_eq!(result.status.exit_status(), 4);
+	}
+
+	#[test]
+	fn arguments_with_a_nul_are_refused_before_forking() {
+		assert!(matches!(spawn("sh", &["sh".to_string(), "a\0b".to_string()]), Err(CError::Invalid)));
+	}
+
+	#[test]
+	fn a_missing_program_exits_with_127() {
+		let mut session = spawn("c_wrapper-no-such-program", &["c_wrapper-no-such-program".to_string()]).unwrap();
+		session.expect_eof().unwrap();
+		assert_eq!(session.wait().unwrap().status.exit_status(), 127);
+	}
+
+	#[test]
+	fn dropping_a_session_reaps_the_program() {
+		let mut session = shell("echo ready; sleep 60");
+		session.expect("ready").unwrap();
+		let pid = session.pid();
+		drop(session);
+		// ECHILD: the process is gone and was already waited for
+		assert!(matches!(waitpid_with_options(pid, libc::WNOHANG), Err(CError::Child)));
+	}
+}
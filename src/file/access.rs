@@ -11,7 +11,7 @@ pub fn access<Path: Into<CString>>(path: Path, check_for: AccessCheck) -> CResul
 			CError::NotFound if check_for == AccessCheck::FileExists => Ok(false),
 			err => Err(err),
 		},
-		bad_return => panic!(format!("Unknown return from access: {}", bad_return)),
+		bad_return => panic!("Unknown return from access: {}", bad_return),
 	}
 }
 
@@ -54,7 +54,7 @@ impl From<libc::c_int> for AccessCheck {
 			Self::Execute
 		}
 		else {
-			panic!(format!("Unknown number given for conversion: {}", bits))
+			panic!("Unknown number given for conversion: {}", bits)
 		}
     }
 }
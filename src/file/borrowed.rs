@@ -0,0 +1,94 @@
+use std::{io::{IoSlice, IoSliceMut}, marker::PhantomData, os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd}};
+
+use crate::c_result::CResult;
+
+use super::{constants, descriptor::{expect_open, read_fd, read_vectored_fd, write_fd, write_vectored_fd}, FileDescriptor};
+
+// A descriptor that is not closed on drop and can't outlive its owner
+#[derive(Clone, Copy)]
+pub struct BorrowedFileDescriptor<'fd> {
+	pub(crate) fd: libc::c_int,
+	owner: PhantomData<&'fd FileDescriptor>,
+}
+
+impl BorrowedFileDescriptor<'_> {
+	pub unsafe fn borrow_raw(fd: RawFd) -> Self {
+		Self {
+			fd,
+			owner: PhantomData,
+		}
+	}
+
+	pub fn try_clone_to_owned(&self) -> CResult<FileDescriptor> {
+		FileDescriptor::wrap_unowned(self.fd, |fd| fd.try_clone())
+	}
+}
+
+// The standard streams stay open for the whole life of the process
+impl BorrowedFileDescriptor<'static> {
+	pub fn stdin() -> Self {
+		unsafe { Self::borrow_raw(constants::STDIN_FILENO) }
+	}
+
+	pub fn stdout() -> Self {
+		unsafe { Self::borrow_raw(constants::STDOUT_FILENO) }
+	}
+
+	pub fn stderr() -> Self {
+		unsafe { Self::borrow_raw(constants::STDERR_FILENO) }
+	}
+}
+
+impl FileDescriptor {
+	pub fn borrow(&self) -> BorrowedFileDescriptor<'_> {
+		unsafe { BorrowedFileDescriptor::borrow_raw(self.fd) }
+	}
+}
+
+impl AsRawFd for BorrowedFileDescriptor<'_> {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd
+	}
+}
+
+impl AsFd for BorrowedFileDescriptor<'_> {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		unsafe { BorrowedFd::borrow_raw(expect_open(self.fd)) }
+	}
+}
+
+impl<'fd> From<BorrowedFd<'fd>> for BorrowedFileDescriptor<'fd> {
+	fn from(fd: BorrowedFd<'fd>) -> Self {
+		unsafe { Self::borrow_raw(fd.as_raw_fd()) }
+	}
+}
+
+impl<'fd> From<BorrowedFileDescriptor<'fd>> for BorrowedFd<'fd> {
+	fn from(fd: BorrowedFileDescriptor<'fd>) -> Self {
+		unsafe { BorrowedFd::borrow_raw(expect_open(fd.fd)) }
+	}
+}
+
+impl std::io::Read for BorrowedFileDescriptor<'_> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		read_fd(self.fd, buf)
+	}
+
+	fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+		read_vectored_fd(self.fd, bufs)
+	}
+}
+
+impl std::io::Write for BorrowedFileDescriptor<'_> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		write_fd(self.fd, buf)
+	}
+
+	fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+		write_vectored_fd(self.fd, bufs)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
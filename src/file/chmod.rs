@@ -1,50 +1,48 @@
-use std::ffi::CString;
+use std::{ffi::CString, os::unix::io::{AsFd, AsRawFd}};
 
 use libc::mode_t;
 
 use crate::{c_result::CResult, c_error::CError};
 
-use super::FileDescriptor;
-
 pub fn chmod<Path: Into<CString>>(pathname: Path, mode: mode_t) -> CResult<()> {
 	let pathname: CString = pathname.into();
 	match unsafe { libc::chmod(pathname.as_ptr(), mode) } {
 		0 => Ok(()),
 		-1 => Err(CError::new_from_errno()),
-		bad_return => panic!(format!(
+		bad_return => panic!(
 			"chmod returned {}, which is different from 0 or -1",
 			bad_return
-		)),
+		),
 	}
 }
 
-pub fn fchmod(fd: &mut FileDescriptor, mode: mode_t) -> CResult<()> {
-	match unsafe { libc::fchmod(fd.fd, mode) } {
+pub fn fchmod<Fd: AsFd>(fd: Fd, mode: mode_t) -> CResult<()> {
+	match unsafe { libc::fchmod(fd.as_fd().as_raw_fd(), mode) } {
 		0 => Ok(()),
 		-1 => Err(CError::new_from_errno()),
-		bad_return => panic!(format!(
+		bad_return => panic!(
 			"fchmod returned {}, which is different from 0 or -1",
 			bad_return
-		)),
+		),
 	}
 }
 
-pub fn chmod_fd(fd: &mut FileDescriptor, mode: mode_t) -> CResult<()> {
+pub fn chmod_fd<Fd: AsFd>(fd: Fd, mode: mode_t) -> CResult<()> {
 	fchmod(fd, mode)	
 }
 
-pub fn fchmod_at<Path: Into<CString>>(dir: &FileDescriptor, pathname: Path, mode: mode_t, flags: libc::c_int) -> CResult<()> {
+pub fn fchmod_at<Dir: AsFd, Path: Into<CString>>(dir: Dir, pathname: Path, mode: mode_t, flags: libc::c_int) -> CResult<()> {
 	let pathname: CString = pathname.into();
-	match unsafe { libc::fchmodat(dir.fd, pathname.as_ptr(), mode, flags) } {
+	match unsafe { libc::fchmodat(dir.as_fd().as_raw_fd(), pathname.as_ptr(), mode, flags) } {
 		0 => Ok(()),
 		-1 => Err(CError::new_from_errno()),
-		bad_return => panic!(format!(
+		bad_return => panic!(
 			"fchmodat returned {}, which is different from 0 or -1",
 			bad_return
-		)),
+		),
 	}
 }
 
-pub fn chmod_fd_at<Path: Into<CString>>(dir: &FileDescriptor, pathname: Path, mode: mode_t, flags: libc::c_int) -> CResult<()> {
+pub fn chmod_fd_at<Dir: AsFd, Path: Into<CString>>(dir: Dir, pathname: Path, mode: mode_t, flags: libc::c_int) -> CResult<()> {
 	fchmod_at(dir, pathname, mode, flags)
 }
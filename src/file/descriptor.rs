@@ -1,8 +1,10 @@
-use std::{io::{Error, ErrorKind}, mem::size_of};
+use std::{fs::File, io::{Error, ErrorKind, IoSlice, IoSliceMut}, mem::size_of, os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd}};
 
 use crate::c_result::CResult;
 use crate::c_error::CError;
 
+use super::{fcntl::StatusFlags, vectored::{readv, writev}};
+
 pub struct FileDescriptor {
 	pub(crate) fd: libc::c_int
 }
@@ -52,33 +54,75 @@ impl FileDescriptor {
 	}
 }
 
+fn read_error(err: CError) -> Error {
+	match err {
+		CError::Again | CError::WouldBlock => Error::from(ErrorKind::WouldBlock),
+		CError::Interrupted => Error::from(ErrorKind::Interrupted),
+		CError::Fault => Error::from(ErrorKind::PermissionDenied),
+		err => Error::from_raw_os_error(err.into())
+	}
+}
+
+fn write_error(err: CError) -> Error {
+	match err {
+		CError::Again | CError::WouldBlock => Error::from(ErrorKind::WouldBlock),
+		CError::DestinationAddressRequired => Error::from(ErrorKind::NotConnected),
+		CError::Fault | CError::Perm => Error::from(ErrorKind::PermissionDenied),
+		CError::Interrupted => Error::from(ErrorKind::Interrupted),
+		CError::BrokenPipe => Error::from(ErrorKind::BrokenPipe),
+		err => Error::from_raw_os_error(err.into())
+	}
+}
+
+pub(crate) fn read_fd(fd: libc::c_int, buf: &mut [u8]) -> std::io::Result<usize> {
+	match unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
+		-1 => Err(read_error(CError::new_from_errno())),
+		n => Ok(n as usize)
+	}
+}
+
+pub(crate) fn write_fd(fd: libc::c_int, buf: &[u8]) -> std::io::Result<usize> {
+	match unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len()) } {
+		-1 => Err(write_error(CError::new_from_errno())),
+		n => Ok(n as usize)
+	}
+}
+
+pub(crate) fn read_vectored_fd(fd: libc::c_int, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+	readv(fd, bufs).map_err(read_error)
+}
+
+pub(crate) fn write_vectored_fd(fd: libc::c_int, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+	writev(fd, bufs).map_err(write_error)
+}
+
 impl std::io::Read for FileDescriptor {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        match unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
-			-1 => Err(match CError::new_from_errno() {
-				CError::Again | CError::WouldBlock => Error::from(ErrorKind::WouldBlock),
-				CError::Interrupted => Error::from(ErrorKind::Interrupted),
-				CError::Fault => Error::from(ErrorKind::PermissionDenied),
-				err => Error::from_raw_os_error(err.into())
-			}),
-			n => Ok(n as usize)
-		}
+		read_fd(self.fd, buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+		read_vectored_fd(self.fd, bufs)
+    }
+}
+
+impl std::io::Read for &FileDescriptor {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		read_fd(self.fd, buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+		read_vectored_fd(self.fd, bufs)
     }
 }
 
 impl std::io::Write for FileDescriptor {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        match unsafe { libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len()) } {
-			-1 => Err(match CError::new_from_errno() {
-				CError::Again | CError::WouldBlock => Error::from(ErrorKind::WouldBlock),
-				CError::DestinationAddressRequired => Error::from(ErrorKind::NotConnected),
-				CError::Fault | CError::Perm => Error::from(ErrorKind::PermissionDenied),
-				CError::Interrupted => Error::from(ErrorKind::Interrupted),
-				CError::BrokenPipe => Error::from(ErrorKind::BrokenPipe),
-				err => Error::from_raw_os_error(err.into())
-			}),
-			n => Ok(n as usize)
-		}
+		write_fd(self.fd, buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+		write_vectored_fd(self.fd, bufs)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -86,16 +130,88 @@ impl std::io::Write for FileDescriptor {
     }
 }
 
+impl std::io::Write for &FileDescriptor {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		write_fd(self.fd, buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+		write_vectored_fd(self.fd, bufs)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// std interop
+impl AsRawFd for FileDescriptor {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd
+	}
+}
+
+// close leaves a descriptor of -1 behind, which BorrowedFd, OwnedFd and File
+// must never hold
+pub(crate) fn expect_open(fd: RawFd) -> RawFd {
+	assert!(fd != -1, "the file descriptor was already closed");
+	fd
+}
+
+impl AsFd for FileDescriptor {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		unsafe { BorrowedFd::borrow_raw(expect_open(self.fd)) }
+	}
+}
+
+impl FromRawFd for FileDescriptor {
+	unsafe fn from_raw_fd(fd: RawFd) -> Self {
+		Self::from_unowned(fd)
+	}
+}
+
+impl IntoRawFd for FileDescriptor {
+	fn into_raw_fd(self) -> RawFd {
+		unsafe { self.to_unowned() }
+	}
+}
+
+impl From<OwnedFd> for FileDescriptor {
+	fn from(fd: OwnedFd) -> Self {
+		unsafe { Self::from_unowned(fd.into_raw_fd()) }
+	}
+}
+
+impl From<FileDescriptor> for OwnedFd {
+	fn from(fd: FileDescriptor) -> Self {
+		expect_open(fd.fd);
+		unsafe { OwnedFd::from_raw_fd(fd.to_unowned()) }
+	}
+}
+
+impl From<File> for FileDescriptor {
+	fn from(file: File) -> Self {
+		unsafe { Self::from_unowned(file.into_raw_fd()) }
+	}
+}
+
+impl From<FileDescriptor> for File {
+	fn from(fd: FileDescriptor) -> Self {
+		expect_open(fd.fd);
+		unsafe { File::from_raw_fd(fd.to_unowned()) }
+	}
+}
+
 impl FileDescriptor {
-	pub fn redirect_from(&mut self, other_fd: &FileDescriptor) -> CResult<()> {
-		match unsafe { libc::dup2(other_fd.fd, self.fd) } {
+	pub fn redirect_from<Fd: AsFd>(&mut self, other_fd: Fd) -> CResult<()> {
+		match unsafe { libc::dup2(other_fd.as_fd().as_raw_fd(), self.fd) } {
 			-1 => Err(CError::new_from_errno()),
 			fd if fd == self.fd => Ok(()),
-			bad_fd => panic!(format!(
+			bad_fd => panic!(
 				"dup2 returned {}, which is different from -1 or fd: {}",
 				bad_fd,
 				self.fd
-			))
+			)
 		}
 	}
 }
@@ -126,28 +242,25 @@ impl FileDescriptor {
 	}
 
 	pub fn set_nonblocking(&mut self, nonblocking: bool) -> CResult<()> {
-		let previous = self.fcntl(libc::F_GETFL)?;
-		let current = if nonblocking {
-			previous | libc::O_NONBLOCK
-		}
-		else {
-			previous & !libc::O_NONBLOCK
-		};
+		let previous = self.status_flags()?;
+		let mut current = previous;
+		current.set(StatusFlags::NONBLOCK, nonblocking);
 		if current != previous {
-			self.fcntl_with_arg(libc::F_SETFL, current)?;
+			self.set_status_flags(current)?;
 		}
 		Ok(())
 	}
 
-	pub fn fcntl(&self, command: libc::c_int) -> CResult<libc::c_int> {
-		match unsafe { libc::fcntl(self.fd, command) } {
+	// The argument type must match what the command expects
+	pub unsafe fn fcntl(&self, command: libc::c_int) -> CResult<libc::c_int> {
+		match libc::fcntl(self.fd, command) {
 			-1 => Err(CError::new_from_errno()),
 			any => Ok(any),
 		}
 	}
 
-	pub fn fcntl_with_arg<Arg>(&self, command: libc::c_int, argument: Arg) -> CResult<libc::c_int> {
-		match unsafe { libc::fcntl(self.fd, command, argument) } {
+	pub unsafe fn fcntl_with_arg<Arg>(&self, command: libc::c_int, argument: Arg) -> CResult<libc::c_int> {
+		match libc::fcntl(self.fd, command, argument) {
 			-1 => Err(CError::new_from_errno()),
 			any => Ok(any),
 		}
@@ -178,8 +291,7 @@ impl FileDescriptor {
 	}
 
 	pub fn read_bytes(&mut self, bytes: usize) -> CResult<Vec<u8>> {
-		let mut result = vec![];
-		result.resize(bytes, 0);
+		let mut result = vec![0; bytes];
 
 		match unsafe { libc::read(self.fd, result.as_mut_ptr() as *mut libc::c_void, result.len())} {
 			-1 => Err(CError::new_from_errno()),
@@ -191,8 +303,7 @@ impl FileDescriptor {
 	}
 
 	pub fn read_exact(&mut self, bytes: usize) -> CResult<Vec<u8>> {
-		let mut result = vec![];
-		result.resize(bytes, 0);
+		let mut result = vec![0; bytes];
 		let mut bytes_read = 0;
 
 		while bytes_read < bytes {
@@ -215,8 +326,8 @@ impl FileDescriptor {
 		let reconstructed_ptr: *mut T = &mut reconstructed;
 		// unsafe {
 			let mut reconstructed_bin_ptr: *mut u8 = core::mem::transmute(reconstructed_ptr);
-			for i in 0..size {
-				*reconstructed_bin_ptr = result[i];
+			for byte in result.iter().take(size) {
+				*reconstructed_bin_ptr = *byte;
 				reconstructed_bin_ptr = reconstructed_bin_ptr.add(1);
 			}
 		// }
@@ -268,3 +379,55 @@ pub mod constants {
 		STDERR_FILENO,
 	};
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pipe::pipe;
+
+	fn closed() -> FileDescriptor {
+		let mut fd = pipe().unwrap().read_fd;
+		fd.close().unwrap();
+		fd
+	}
+
+	#[test]
+	#[should_panic(expected = "already closed")]
+	fn closed_descriptor_cannot_be_borrowed_as_fd() {
+		let _ = closed().as_fd();
+	}
+
+	#[test]
+	#[should_panic(expected = "already closed")]
+	fn closed_descriptor_cannot_become_owned_fd() {
+		let _ = OwnedFd::from(closed());
+	}
+
+	#[test]
+	#[should_panic(expected = "already closed")]
+	fn closed_descriptor_cannot_become_file() {
+		let _ = File::from(closed());
+	}
+
+	#[test]
+	#[should_panic(expected = "already closed")]
+	fn borrowed_closed_descriptor_cannot_be_borrowed_as_fd() {
+		let _ = closed().borrow().as_fd();
+	}
+
+	#[test]
+	#[should_panic(expected = "already closed")]
+	fn borrowed_closed_descriptor_cannot_become_borrowed_fd() {
+		let fd = closed();
+		let _ = BorrowedFd::from(fd.borrow());
+	}
+
+	#[test]
+	fn open_descriptor_round_trips_through_owned_fd() {
+		let pipe = pipe().unwrap();
+		let raw = pipe.read_fd.fd;
+		let owned = OwnedFd::from(pipe.read_fd);
+		assert_eq!(owned.as_raw_fd(), raw);
+		assert_eq!(FileDescriptor::from(owned).fd, raw);
+	}
+}
@@ -0,0 +1,575 @@
+use std::{cell::Cell, convert::TryInto, ffi::{CStr, CString, OsStr, OsString}, os::unix::{ffi::OsStrExt, io::{AsFd, AsRawFd, BorrowedFd, IntoRawFd, RawFd}}, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+#[cfg(target_os = "linux")]
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult};
+
+use super::{
+	access::AccessCheck,
+	chmod::fchmod_at,
+	open::{flags::{O_CLOEXEC, O_CREAT, O_DIRECTORY, O_RDONLY}, open_with_flags, openat_with_flags, openat_with_mode},
+	stat::{fstatat, FileType, Metadata},
+	BorrowedFileDescriptor,
+	FileDescriptor,
+};
+
+#[cfg(target_os = "linux")]
+pub static DEFAULT_GETDENTS_BUFFER_SIZE: usize = 32 * 1024;
+
+// Where entries are read from; entries keep it alive so they can stat
+// themselves relative to the directory
+enum Handle {
+	Stream(*mut libc::DIR),
+	#[cfg(target_os = "linux")]
+	Getdents(FileDescriptor),
+}
+
+impl Handle {
+	fn fd(&self) -> libc::c_int {
+		match self {
+			Handle::Stream(stream) => unsafe { libc::dirfd(*stream) },
+			#[cfg(target_os = "linux")]
+			Handle::Getdents(fd) => fd.fd,
+		}
+	}
+}
+
+// The stream is only read through the ReadDir that owns the iteration;
+// entries on other threads just call dirfd on it, which doesn't change it
+unsafe impl Send for Handle {}
+unsafe impl Sync for Handle {}
+
+impl Drop for Handle {
+	fn drop(&mut self) {
+		if let Handle::Stream(stream) = self {
+			unsafe { libc::closedir(*stream) };
+		}
+	}
+}
+
+// Entries read by getdents64 that haven't been returned yet
+#[cfg(target_os = "linux")]
+struct GetdentsBuffer {
+	buffer: Vec<u8>,
+	position: usize,
+	length: usize,
+}
+
+// Iterates over a directory, skipping the . and .. entries
+pub struct ReadDir {
+	handle: Arc<Handle>,
+	#[cfg(target_os = "linux")]
+	getdents: Option<GetdentsBuffer>,
+	finished: bool,
+}
+
+impl ReadDir {
+	pub fn open<Path: Into<CString>>(pathname: Path) -> CResult<Self> {
+		Self::from_fd(open_with_flags(pathname, O_RDONLY | O_DIRECTORY | O_CLOEXEC)?)
+	}
+
+	// fd must have been opened with O_DIRECTORY, or at least refer to a directory
+	pub fn from_fd(fd: FileDescriptor) -> CResult<Self> {
+		let fd = fd.into_raw_fd();
+		let stream = unsafe { libc::fdopendir(fd) };
+		if stream.is_null() {
+			let err = CError::new_from_errno();
+			drop(unsafe { FileDescriptor::from_unowned(fd) });
+			return Err(err);
+		}
+		Ok(Self::new(Handle::Stream(stream)))
+	}
+
+	// Reads entries with getdents64, buffer_size bytes at a time, which needs
+	// far fewer system calls for huge directories
+	#[cfg(target_os = "linux")]
+	pub fn with_getdents(fd: FileDescriptor, buffer_size: usize) -> Self {
+		let mut read_dir = Self::new(Handle::Getdents(fd));
+		read_dir.getdents = Some(GetdentsBuffer {
+			buffer: vec![0; buffer_size],
+			position: 0,
+			length: 0,
+		});
+		read_dir
+	}
+
+	fn new(handle: Handle) -> Self {
+		Self {
+			handle: Arc::new(handle),
+			#[cfg(target_os = "linux")]
+			getdents: None,
+			finished: false,
+		}
+	}
+
+	fn entry(&self, name: CString, ino: u64, d_type: u8) -> DirEntry {
+		DirEntry {
+			dir: self.handle.clone(),
+			name,
+			ino,
+			file_type: Cell::new(file_type_from_d_type(d_type)),
+		}
+	}
+
+	fn next_from_stream(&mut self, stream: *mut libc::DIR) -> Option<CResult<DirEntry>> {
+		// readdir only tells the end apart from an error through errno
+		errno::set_errno(errno::Errno(0));
+		let dirent = unsafe { libc::readdir(stream) };
+		if dirent.is_null() {
+			return match errno::errno().0 {
+				0 => None,
+				errno => Some(Err(CError::from(errno))),
+			};
+		}
+		let dirent = unsafe { &*dirent };
+		let name = unsafe { CStr::from_ptr(dirent.d_name.as_ptr()) };
+		Some(Ok(self.entry(name.to_owned(), dirent.d_ino as u64, dirent.d_type)))
+	}
+
+	#[cfg(target_os = "linux")]
+	fn next_from_getdents(&mut self) -> Option<CResult<DirEntry>> {
+		let fd = self.handle.fd();
+		let getdents = self.getdents.as_mut()?;
+		if getdents.position >= getdents.length {
+			let result = unsafe {
+				libc::syscall(
+					libc::SYS_getdents64,
+					fd,
+					getdents.buffer.as_mut_ptr(),
+					getdents.buffer.len(),
+				)
+			};
+			match result {
+				-1 => return Some(Err(CError::new_from_errno())),
+				0 => return None,
+				length => {
+					getdents.position = 0;
+					getdents.length = length as usize;
+				},
+			}
+		}
+
+		// struct linux_dirent64 { u64 d_ino; s64 d_off; u16 d_reclen; u8 d_type; char d_name[]; }
+		let record = &getdents.buffer[getdents.position..getdents.length];
+		let ino = u64::from_ne_bytes(record[0..8].try_into().unwrap());
+		let record_length = u16::from_ne_bytes(record[16..18].try_into().unwrap()) as usize;
+		let d_type = record[18];
+		let name = CStr::from_bytes_until_nul(&record[19..record_length]).unwrap().to_owned();
+		getdents.position += record_length;
+
+		Some(Ok(self.entry(name, ino, d_type)))
+	}
+}
+
+impl Iterator for ReadDir {
+	type Item = CResult<DirEntry>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		while !self.finished {
+			let next = match *self.handle {
+				Handle::Stream(stream) => self.next_from_stream(stream),
+				#[cfg(target_os = "linux")]
+				Handle::Getdents(_) => self.next_from_getdents(),
+			};
+			match next {
+				Some(Ok(entry)) if entry.is_dot_or_dot_dot() => continue,
+				Some(Ok(entry)) => return Some(Ok(entry)),
+				Some(Err(err)) => {
+					self.finished = true;
+					return Some(Err(err));
+				},
+				None => self.finished = true,
+			}
+		}
+		None
+	}
+}
+
+fn file_type_from_d_type(d_type: u8) -> Option<FileType> {
+	match d_type {
+		libc::DT_REG => Some(FileType::Regular),
+		libc::DT_DIR => Some(FileType::Directory),
+		libc::DT_LNK => Some(FileType::Symlink),
+		libc::DT_FIFO => Some(FileType::Fifo),
+		libc::DT_SOCK => Some(FileType::Socket),
+		libc::DT_CHR => Some(FileType::CharacterDevice),
+		libc::DT_BLK => Some(FileType::BlockDevice),
+		_ => None,
+	}
+}
+
+pub struct DirEntry {
+	dir: Arc<Handle>,
+	name: CString,
+	ino: u64,
+	file_type: Cell<Option<FileType>>,
+}
+
+impl DirEntry {
+	pub fn name(&self) -> &CStr {
+		&self.name
+	}
+
+	pub fn file_name(&self) -> OsString {
+		OsStr::from_bytes(self.name.as_bytes()).to_owned()
+	}
+
+	pub fn into_name(self) -> CString {
+		self.name
+	}
+
+	pub fn ino(&self) -> u64 {
+		self.ino
+	}
+
+	// The type, if it is known without a stat call
+	pub fn d_type(&self) -> Option<FileType> {
+		self.file_type.get()
+	}
+
+	// Falls back to stat when the filesystem doesn't report types; symlinks
+	// are not followed
+	pub fn file_type(&self) -> CResult<FileType> {
+		match self.file_type.get() {
+			Some(file_type) => Ok(file_type),
+			None => {
+				let file_type = self.metadata()?.file_type;
+				self.file_type.set(Some(file_type));
+				Ok(file_type)
+			},
+		}
+	}
+
+	pub fn metadata(&self) -> CResult<Metadata> {
+		let dir = unsafe { BorrowedFileDescriptor::borrow_raw(self.dir.fd()) };
+		fstatat(Some(dir), self.name.clone(), flags::AT_SYMLINK_NOFOLLOW)
+	}
+
+	fn is_dot_or_dot_dot(&self) -> bool {
+		matches!(self.name.as_bytes(), b"." | b"..")
+	}
+}
+
+impl std::fmt::Debug for DirEntry {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("DirEntry")
+			.field("name", &self.name)
+			.field("ino", &self.ino)
+			.field("file_type", &self.file_type.get())
+			.finish()
+	}
+}
+
+pub fn read_dir<Path: Into<CString>>(pathname: Path) -> CResult<ReadDir> {
+	ReadDir::open(pathname)
+}
+
+#[cfg(target_os = "linux")]
+bitflags! {
+	pub struct RenameFlags: libc::c_uint {
+		// Fail with AlreadyExists instead of replacing the destination
+		const NOREPLACE = libc::RENAME_NOREPLACE;
+		// Atomically swap the source and the destination, which must both exist
+		const EXCHANGE = libc::RENAME_EXCHANGE;
+		// Leave a whiteout in place of the source, for overlay filesystems
+		const WHITEOUT = libc::RENAME_WHITEOUT;
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampUpdate {
+	Now,
+	// Leave the timestamp unchanged
+	Omit,
+	At(SystemTime),
+}
+
+impl From<TimestampUpdate> for libc::timespec {
+	fn from(update: TimestampUpdate) -> Self {
+		match update {
+			TimestampUpdate::Now => libc::timespec {
+				tv_sec: 0,
+				tv_nsec: libc::UTIME_NOW,
+			},
+			TimestampUpdate::Omit => libc::timespec {
+				tv_sec: 0,
+				tv_nsec: libc::UTIME_OMIT,
+			},
+			TimestampUpdate::At(time) => {
+				// Times before the epoch are whole seconds back plus a
+				// forward nanosecond part, like timespec expects
+				let (seconds, nanoseconds) = match time.duration_since(UNIX_EPOCH) {
+					Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+					Err(err) => {
+						let before = err.duration();
+						match before.subsec_nanos() {
+							0 => (-(before.as_secs() as i64), 0),
+							nanoseconds => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanoseconds),
+						}
+					},
+				};
+				libc::timespec {
+					tv_sec: seconds as libc::time_t,
+					tv_nsec: nanoseconds as libc::c_long,
+				}
+			},
+		}
+	}
+}
+
+fn check_at_result(name: &str, result: libc::c_int) -> CResult<()> {
+	match result {
+		0 => Ok(()),
+		-1 => Err(CError::new_from_errno()),
+		bad_return => panic!("{} returned {}, which is different from 0 or -1", name, bad_return),
+	}
+}
+
+pub(crate) fn readlinkat(dir: libc::c_int, pathname: &CStr) -> CResult<CString> {
+	let mut buffer: Vec<u8> = vec![0; 256];
+	loop {
+		let length = match unsafe { libc::readlinkat(dir, pathname.as_ptr(), buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } {
+			-1 => return Err(CError::new_from_errno()),
+			length => length as usize,
+		};
+		// A full buffer may mean the target was cut short
+		if length < buffer.len() {
+			buffer.truncate(length);
+			return Ok(CString::new(buffer).unwrap());
+		}
+		buffer.resize(buffer.len() * 2, 0);
+	}
+}
+
+// A directory handle that every path is resolved relative to, so that the
+// directory can be moved or renamed without affecting operations in it
+pub struct Dir {
+	fd: FileDescriptor,
+}
+
+impl Dir {
+	pub fn from_path<Path: Into<CString>>(pathname: Path) -> CResult<Self> {
+		Ok(Self {
+			fd: open_with_flags(pathname, O_RDONLY | O_DIRECTORY | O_CLOEXEC)?,
+		})
+	}
+
+	// fd should refer to a directory, for example one opened with O_DIRECTORY
+	pub fn from_fd(fd: FileDescriptor) -> Self {
+		Self {
+			fd,
+		}
+	}
+
+	pub fn current() -> CResult<Self> {
+		Self::from_path(CString::new(".").unwrap())
+	}
+
+	pub fn fd(&self) -> &FileDescriptor {
+		&self.fd
+	}
+
+	pub fn into_fd(self) -> FileDescriptor {
+		self.fd
+	}
+
+	pub fn open<Path: Into<CString>>(&self, pathname: Path, flags: libc::c_int) -> CResult<FileDescriptor> {
+		openat_with_flags(&self.fd, pathname, flags)
+	}
+
+	// Adds O_CREAT to flags
+	pub fn create<Path: Into<CString>>(&self, pathname: Path, flags: libc::c_int, mode: libc::mode_t) -> CResult<FileDescriptor> {
+		openat_with_mode(&self.fd, pathname, flags | O_CREAT, mode)
+	}
+
+	pub fn open_dir<Path: Into<CString>>(&self, pathname: Path) -> CResult<Dir> {
+		Ok(Dir {
+			fd: self.open(pathname, O_RDONLY | O_DIRECTORY | O_CLOEXEC)?,
+		})
+	}
+
+	// Reads through a new open file description, so iterating doesn't move
+	// the position of this handle
+	pub fn read_dir(&self) -> CResult<ReadDir> {
+		ReadDir::from_fd(self.open(CString::new(".").unwrap(), O_RDONLY | O_DIRECTORY | O_CLOEXEC)?)
+	}
+
+	pub fn mkdir<Path: Into<CString>>(&self, pathname: Path, mode: libc::mode_t) -> CResult<()> {
+		let pathname: CString = pathname.into();
+		check_at_result("mkdirat", unsafe { libc::mkdirat(self.fd.fd, pathname.as_ptr(), mode) })
+	}
+
+	pub fn unlink<Path: Into<CString>>(&self, pathname: Path) -> CResult<()> {
+		let pathname: CString = pathname.into();
+		check_at_result("unlinkat", unsafe { libc::unlinkat(self.fd.fd, pathname.as_ptr(), 0) })
+	}
+
+	pub fn rmdir<Path: Into<CString>>(&self, pathname: Path) -> CResult<()> {
+		let pathname: CString = pathname.into();
+		check_at_result("unlinkat", unsafe { libc::unlinkat(self.fd.fd, pathname.as_ptr(), flags::AT_REMOVEDIR) })
+	}
+
+	pub fn rename<Old: Into<CString>, New: Into<CString>>(&self, old_pathname: Old, new_pathname: New) -> CResult<()> {
+		self.rename_to(old_pathname, &self.fd, new_pathname)
+	}
+
+	pub fn rename_to<Old: Into<CString>, NewDir: AsFd, New: Into<CString>>(&self, old_pathname: Old, new_dir: NewDir, new_pathname: New) -> CResult<()> {
+		let old_pathname: CString = old_pathname.into();
+		let new_pathname: CString = new_pathname.into();
+		check_at_result("renameat", unsafe {
+			libc::renameat(self.fd.fd, old_pathname.as_ptr(), new_dir.as_fd().as_raw_fd(), new_pathname.as_ptr())
+		})
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn rename_with_flags<Old: Into<CString>, NewDir: AsFd, New: Into<CString>>(&self, old_pathname: Old, new_dir: NewDir, new_pathname: New, flags: RenameFlags) -> CResult<()> {
+		let old_pathname: CString = old_pathname.into();
+		let new_pathname: CString = new_pathname.into();
+		// Called directly since not every libc has a renameat2 wrapper
+		let result = unsafe {
+			libc::syscall(
+				libc::SYS_renameat2,
+				self.fd.fd,
+				old_pathname.as_ptr(),
+				new_dir.as_fd().as_raw_fd(),
+				new_pathname.as_ptr(),
+				flags.bits(),
+			)
+		};
+		check_at_result("renameat2", result as libc::c_int)
+	}
+
+	pub fn link<Old: Into<CString>, New: Into<CString>>(&self, old_pathname: Old, new_pathname: New) -> CResult<()> {
+		self.link_to(old_pathname, &self.fd, new_pathname, 0)
+	}
+
+	// flags may contain AT_SYMLINK_FOLLOW to link to the target of a symlink
+	pub fn link_to<Old: Into<CString>, NewDir: AsFd, New: Into<CString>>(&self, old_pathname: Old, new_dir: NewDir, new_pathname: New, flags: libc::c_int) -> CResult<()> {
+		let old_pathname: CString = old_pathname.into();
+		let new_pathname: CString = new_pathname.into();
+		check_at_result("linkat", unsafe {
+			libc::linkat(self.fd.fd, old_pathname.as_ptr(), new_dir.as_fd().as_raw_fd(), new_pathname.as_ptr(), flags)
+		})
+	}
+
+	// target is stored as is, and is not resolved relative to this directory
+	pub fn symlink<Target: Into<CString>, Path: Into<CString>>(&self, target: Target, link_pathname: Path) -> CResult<()> {
+		let target: CString = target.into();
+		let link_pathname: CString = link_pathname.into();
+		check_at_result("symlinkat", unsafe { libc::symlinkat(target.as_ptr(), self.fd.fd, link_pathname.as_ptr()) })
+	}
+
+	pub fn readlink<Path: Into<CString>>(&self, pathname: Path) -> CResult<CString> {
+		readlinkat(self.fd.fd, &pathname.into())
+	}
+
+	// flags may contain AT_SYMLINK_NOFOLLOW
+	pub fn stat<Path: Into<CString>>(&self, pathname: Path, flags: libc::c_int) -> CResult<Metadata> {
+		fstatat(Some(&self.fd), pathname, flags)
+	}
+
+	// flags may contain AT_EACCESS, to check with the effective rather than
+	// the real ids, and AT_SYMLINK_NOFOLLOW
+	pub fn access<Path: Into<CString>>(&self, pathname: Path, check_for: AccessCheck, flags: libc::c_int) -> CResult<bool> {
+		let pathname: CString = pathname.into();
+		match unsafe { libc::faccessat(self.fd.fd, pathname.as_ptr(), check_for.into(), flags) } {
+			0 => Ok(true),
+			-1 => match CError::new_from_errno() {
+				CError::PermissionDenied if check_for != AccessCheck::FileExists => Ok(false),
+				CError::NotFound if check_for == AccessCheck::FileExists => Ok(false),
+				err => Err(err),
+			},
+			bad_return => panic!("Unknown return from faccessat: {}", bad_return),
+		}
+	}
+
+	pub fn chmod<Path: Into<CString>>(&self, pathname: Path, mode: libc::mode_t, flags: libc::c_int) -> CResult<()> {
+		fchmod_at(&self.fd, pathname, mode, flags)
+	}
+
+	// None leaves the owner or group unchanged
+	pub fn chown<Path: Into<CString>>(&self, pathname: Path, uid: Option<libc::uid_t>, gid: Option<libc::gid_t>, flags: libc::c_int) -> CResult<()> {
+		let pathname: CString = pathname.into();
+		let uid = uid.unwrap_or(libc::uid_t::MAX);
+		let gid = gid.unwrap_or(libc::gid_t::MAX);
+		check_at_result("fchownat", unsafe { libc::fchownat(self.fd.fd, pathname.as_ptr(), uid, gid, flags) })
+	}
+
+	pub fn utimens<Path: Into<CString>>(&self, pathname: Path, accessed: TimestampUpdate, modified: TimestampUpdate, flags: libc::c_int) -> CResult<()> {
+		let pathname: CString = pathname.into();
+		let times: [libc::timespec; 2] = [accessed.into(), modified.into()];
+		check_at_result("utimensat", unsafe { libc::utimensat(self.fd.fd, pathname.as_ptr(), times.as_ptr(), flags) })
+	}
+
+	// mode includes the file type, such as S_IFIFO or S_IFCHR
+	pub fn mknod<Path: Into<CString>>(&self, pathname: Path, mode: libc::mode_t, dev: libc::dev_t) -> CResult<()> {
+		let pathname: CString = pathname.into();
+		check_at_result("mknodat", unsafe { libc::mknodat(self.fd.fd, pathname.as_ptr(), mode, dev) })
+	}
+}
+
+impl AsRawFd for Dir {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.fd
+	}
+}
+
+impl AsFd for Dir {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.fd.as_fd()
+	}
+}
+
+impl From<Dir> for FileDescriptor {
+	fn from(dir: Dir) -> Self {
+		dir.fd
+	}
+}
+
+pub mod flags {
+	pub use libc::{
+		AT_EACCESS,
+		AT_REMOVEDIR,
+		AT_SYMLINK_FOLLOW,
+		AT_SYMLINK_NOFOLLOW,
+	};
+
+	#[cfg(target_os = "linux")]
+	pub use libc::AT_EMPTY_PATH;
+
+	pub use libc::{
+		S_IFIFO,
+		S_IFCHR,
+		S_IFBLK,
+		S_IFREG,
+		S_IFSOCK,
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn entries_can_be_read_and_inspected_on_another_thread() {
+		let read_dir = read_dir(CString::new("/").unwrap()).unwrap();
+		let entries = std::thread::spawn(move || read_dir.collect::<CResult<Vec<DirEntry>>>().unwrap())
+			.join()
+			.unwrap();
+		let entry = entries.into_iter().find(|entry| entry.name().to_bytes() == b"tmp").unwrap();
+		let file_type = std::thread::spawn(move || entry.file_type().unwrap()).join().unwrap();
+		assert_eq!(file_type, FileType::Directory);
+	}
+
+	#[test]
+	fn directory_descriptors_are_closed_on_exec() {
+		let close_on_exec = |fd: libc::c_int| unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC != 0;
+		let root = CString::new("/").unwrap();
+		assert!(close_on_exec(read_dir(root.clone()).unwrap().handle.fd()));
+		let dir = Dir::from_path(root).unwrap();
+		assert!(close_on_exec(dir.fd.fd));
+		assert!(close_on_exec(dir.open_dir(CString::new("tmp").unwrap()).unwrap().fd.fd));
+		assert!(close_on_exec(dir.read_dir().unwrap().handle.fd()));
+	}
+}
@@ -0,0 +1,149 @@
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult};
+
+use super::FileDescriptor;
+
+bitflags! {
+	// The file status flags that F_SETFL can change
+	pub struct StatusFlags: libc::c_int {
+		const APPEND = libc::O_APPEND;
+		const NONBLOCK = libc::O_NONBLOCK;
+		const ASYNC = libc::O_ASYNC;
+		#[cfg(target_os = "linux")]
+		const DIRECT = libc::O_DIRECT;
+		#[cfg(target_os = "linux")]
+		const NOATIME = libc::O_NOATIME;
+	}
+}
+
+impl FileDescriptor {
+	pub fn get_cloexec(&self) -> CResult<bool> {
+		let flags = unsafe { self.fcntl(libc::F_GETFD) }?;
+		Ok(flags & libc::FD_CLOEXEC != 0)
+	}
+
+	pub fn set_cloexec(&self, cloexec: bool) -> CResult<()> {
+		let previous = unsafe { self.fcntl(libc::F_GETFD) }?;
+		let current = if cloexec {
+			previous | libc::FD_CLOEXEC
+		}
+		else {
+			previous & !libc::FD_CLOEXEC
+		};
+		if current != previous {
+			unsafe { self.fcntl_with_arg(libc::F_SETFD, current) }?;
+		}
+		Ok(())
+	}
+
+	// Bits outside StatusFlags, like the access mode, are dropped
+	pub fn status_flags(&self) -> CResult<StatusFlags> {
+		let flags = unsafe { self.fcntl(libc::F_GETFL) }?;
+		Ok(StatusFlags::from_bits_truncate(flags))
+	}
+
+	pub fn set_status_flags(&self, flags: StatusFlags) -> CResult<()> {
+		unsafe { self.fcntl_with_arg(libc::F_SETFL, flags.bits()) }?;
+		Ok(())
+	}
+
+	// Returns the access mode, i.e. one of O_RDONLY, O_WRONLY or O_RDWR
+	pub fn access_mode(&self) -> CResult<libc::c_int> {
+		let flags = unsafe { self.fcntl(libc::F_GETFL) }?;
+		Ok(flags & libc::O_ACCMODE)
+	}
+
+	// Duplicates into the lowest free descriptor that is at least min_fd
+	pub fn dup_min(&self, min_fd: libc::c_int, cloexec: bool) -> CResult<FileDescriptor> {
+		let command = if cloexec {
+			libc::F_DUPFD_CLOEXEC
+		}
+		else {
+			libc::F_DUPFD
+		};
+		let fd = unsafe { self.fcntl_with_arg(command, min_fd) }?;
+		Ok(unsafe { FileDescriptor::from_unowned(fd) })
+	}
+
+	// Like redirect_from, but can also mark the new descriptor close-on-exec.
+	// Fails with Invalid if both refer to the same descriptor.
+	#[cfg(target_os = "linux")]
+	pub fn redirect_from_with_cloexec<Fd: std::os::unix::io::AsFd>(&mut self, other_fd: Fd, cloexec: bool) -> CResult<()> {
+		use std::os::unix::io::AsRawFd;
+
+		let flags = if cloexec { libc::O_CLOEXEC } else { 0 };
+		match unsafe { libc::dup3(other_fd.as_fd().as_raw_fd(), self.fd, flags) } {
+			-1 => Err(CError::new_from_errno()),
+			fd if fd == self.fd => Ok(()),
+			bad_fd => panic!(
+				"dup3 returned {}, which is different from -1 or fd: {}",
+				bad_fd,
+				self.fd
+			)
+		}
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn pipe_size(&self) -> CResult<usize> {
+		unsafe { self.fcntl(libc::F_GETPIPE_SZ) }.map(|size| size as usize)
+	}
+
+	// The kernel may round the size up; the actual size is returned
+	#[cfg(target_os = "linux")]
+	pub fn set_pipe_size(&self, size: usize) -> CResult<usize> {
+		if size > libc::c_int::MAX as usize {
+			return Err(CError::Invalid);
+		}
+		unsafe { self.fcntl_with_arg(libc::F_SETPIPE_SZ, size as libc::c_int) }.map(|size| size as usize)
+	}
+
+	// The process (positive) or process group (negative) that receives
+	// SIGIO/SIGURG when O_ASYNC is set
+	pub fn owner(&self) -> CResult<libc::pid_t> {
+		unsafe { self.fcntl(libc::F_GETOWN) }
+	}
+
+	pub fn set_owner(&self, owner: libc::pid_t) -> CResult<()> {
+		unsafe { self.fcntl_with_arg(libc::F_SETOWN, owner) }.map(|_| ())
+	}
+
+	// 0 means SIGIO
+	#[cfg(target_os = "linux")]
+	pub fn io_signal(&self) -> CResult<libc::c_int> {
+		unsafe { self.fcntl(constants::F_GETSIG) }
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn set_io_signal(&self, signal: libc::c_int) -> CResult<()> {
+		unsafe { self.fcntl_with_arg(constants::F_SETSIG, signal) }.map(|_| ())
+	}
+}
+
+pub mod constants {
+	pub use libc::{
+		F_GETFD,
+		F_SETFD,
+		F_GETFL,
+		F_SETFL,
+		F_DUPFD,
+		F_DUPFD_CLOEXEC,
+		F_GETOWN,
+		F_SETOWN,
+		FD_CLOEXEC,
+	};
+
+	#[cfg(target_os = "linux")]
+	pub use libc::{
+		F_GETPIPE_SZ,
+		F_SETPIPE_SZ,
+		F_ADD_SEALS,
+		F_GET_SEALS,
+	};
+
+	// Missing from libc; the values are the same on every Linux architecture
+	#[cfg(target_os = "linux")]
+	pub const F_SETSIG: libc::c_int = 10;
+	#[cfg(target_os = "linux")]
+	pub const F_GETSIG: libc::c_int = 11;
+}
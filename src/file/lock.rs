@@ -0,0 +1,301 @@
+use std::time::{Duration, Instant};
+
+use crate::{c_error::CError, c_result::CResult};
+
+use super::FileDescriptor;
+
+static INITIAL_RETRY_DELAY: Duration = Duration::from_millis(1);
+static MAX_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+	Shared,
+	Exclusive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockRange {
+	pub start: libc::off_t,
+	// 0 extends the range to the end of the file, however large it grows
+	pub length: libc::off_t,
+}
+
+impl LockRange {
+	pub fn whole_file() -> Self {
+		Self {
+			start: 0,
+			length: 0,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConflictingLock {
+	pub kind: LockKind,
+	pub range: LockRange,
+	// -1 for open file description locks, which have no owning process
+	pub pid: libc::pid_t,
+}
+
+// Which fcntl commands a record lock uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordLockStyle {
+	Posix,
+	#[cfg(target_os = "linux")]
+	OpenFileDescription,
+}
+
+impl RecordLockStyle {
+	fn set_command(&self) -> libc::c_int {
+		match self {
+			RecordLockStyle::Posix => libc::F_SETLK,
+			#[cfg(target_os = "linux")]
+			RecordLockStyle::OpenFileDescription => libc::F_OFD_SETLK,
+		}
+	}
+
+	fn set_wait_command(&self) -> libc::c_int {
+		match self {
+			RecordLockStyle::Posix => libc::F_SETLKW,
+			#[cfg(target_os = "linux")]
+			RecordLockStyle::OpenFileDescription => libc::F_OFD_SETLKW,
+		}
+	}
+
+	fn get_command(&self) -> libc::c_int {
+		match self {
+			RecordLockStyle::Posix => libc::F_GETLK,
+			#[cfg(target_os = "linux")]
+			RecordLockStyle::OpenFileDescription => libc::F_OFD_GETLK,
+		}
+	}
+}
+
+fn lock_type(kind: Option<LockKind>) -> libc::c_short {
+	(match kind {
+		Some(LockKind::Shared) => libc::F_RDLCK,
+		Some(LockKind::Exclusive) => libc::F_WRLCK,
+		None => libc::F_UNLCK,
+	}) as libc::c_short
+}
+
+fn new_flock(kind: Option<LockKind>, range: LockRange) -> libc::flock {
+	// OFD locks require every other field, including l_pid, to be zero
+	let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+	lock.l_type = lock_type(kind);
+	lock.l_whence = libc::SEEK_SET as libc::c_short;
+	lock.l_start = range.start;
+	lock.l_len = range.length;
+	lock
+}
+
+// Retries attempt with a growing delay until it succeeds or timeout elapses
+fn retry_until<T, Attempt: FnMut() -> CResult<Option<T>>>(timeout: Duration, mut attempt: Attempt) -> CResult<Option<T>> {
+	let deadline = Instant::now() + timeout;
+	let mut delay = INITIAL_RETRY_DELAY;
+	loop {
+		if let Some(result) = attempt()? {
+			return Ok(Some(result));
+		}
+		let now = Instant::now();
+		if now >= deadline {
+			return Ok(None);
+		}
+		std::thread::sleep(delay.min(deadline - now));
+		delay = (delay * 2).min(MAX_RETRY_DELAY);
+	}
+}
+
+// Releases a flock lock when dropped
+pub struct FlockGuard<'fd> {
+	fd: &'fd FileDescriptor,
+	locked: bool,
+}
+
+impl FlockGuard<'_> {
+	pub fn unlock(mut self) -> CResult<()> {
+		self.locked = false;
+		self.fd.flock_operation(libc::LOCK_UN)
+	}
+}
+
+impl Drop for FlockGuard<'_> {
+	fn drop(&mut self) {
+		if self.locked {
+			let _ = self.fd.flock_operation(libc::LOCK_UN);
+		}
+	}
+}
+
+// Releases a record lock on its range when dropped
+pub struct RecordLockGuard<'fd> {
+	fd: &'fd FileDescriptor,
+	range: LockRange,
+	style: RecordLockStyle,
+	locked: bool,
+}
+
+impl RecordLockGuard<'_> {
+	pub fn range(&self) -> LockRange {
+		self.range
+	}
+
+	pub fn unlock(mut self) -> CResult<()> {
+		self.locked = false;
+		self.fd.set_record_lock(self.style, None, self.range, false)
+	}
+}
+
+impl Drop for RecordLockGuard<'_> {
+	fn drop(&mut self) {
+		if self.locked {
+			let _ = self.fd.set_record_lock(self.style, None, self.range, false);
+		}
+	}
+}
+
+// flock
+impl FileDescriptor {
+	fn flock_operation(&self, operation: libc::c_int) -> CResult<()> {
+		loop {
+			match unsafe { libc::flock(self.fd, operation) } {
+				0 => return Ok(()),
+				-1 => match CError::new_from_errno() {
+					CError::Interrupted => continue,
+					err => return Err(err),
+				},
+				bad_return => panic!("flock returned {}, which is different from 0 or -1", bad_return),
+			}
+		}
+	}
+
+	fn flock_kind(kind: LockKind) -> libc::c_int {
+		match kind {
+			LockKind::Shared => libc::LOCK_SH,
+			LockKind::Exclusive => libc::LOCK_EX,
+		}
+	}
+
+	pub fn flock(&self, kind: LockKind) -> CResult<FlockGuard<'_>> {
+		self.flock_operation(Self::flock_kind(kind))?;
+		Ok(FlockGuard {
+			fd: self,
+			locked: true,
+		})
+	}
+
+	// Returns None if the lock is held elsewhere
+	pub fn try_flock(&self, kind: LockKind) -> CResult<Option<FlockGuard<'_>>> {
+		match self.flock_operation(Self::flock_kind(kind) | libc::LOCK_NB) {
+			Ok(()) => Ok(Some(FlockGuard {
+				fd: self,
+				locked: true,
+			})),
+			Err(CError::WouldBlock) | Err(CError::Again) => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+
+	pub fn flock_timeout(&self, kind: LockKind, timeout: Duration) -> CResult<Option<FlockGuard<'_>>> {
+		retry_until(timeout, || self.try_flock(kind))
+	}
+}
+
+// POSIX record locks, owned by the process
+impl FileDescriptor {
+	fn set_record_lock(&self, style: RecordLockStyle, kind: Option<LockKind>, range: LockRange, wait: bool) -> CResult<()> {
+		let lock = new_flock(kind, range);
+		let command = if wait {
+			style.set_wait_command()
+		}
+		else {
+			style.set_command()
+		};
+		loop {
+			match unsafe { self.fcntl_with_arg(command, &lock as *const libc::flock) } {
+				Ok(_) => return Ok(()),
+				Err(CError::Interrupted) => continue,
+				Err(err) => return Err(err),
+			}
+		}
+	}
+
+	fn record_lock(&self, style: RecordLockStyle, kind: LockKind, range: LockRange) -> CResult<RecordLockGuard<'_>> {
+		self.set_record_lock(style, Some(kind), range, true)?;
+		Ok(RecordLockGuard {
+			fd: self,
+			range,
+			style,
+			locked: true,
+		})
+	}
+
+	fn try_record_lock(&self, style: RecordLockStyle, kind: LockKind, range: LockRange) -> CResult<Option<RecordLockGuard<'_>>> {
+		match self.set_record_lock(style, Some(kind), range, false) {
+			Ok(()) => Ok(Some(RecordLockGuard {
+				fd: self,
+				range,
+				style,
+				locked: true,
+			})),
+			// POSIX allows either error for a conflicting lock
+			Err(CError::Again) | Err(CError::PermissionDenied) => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+
+	fn get_record_lock(&self, style: RecordLockStyle, kind: LockKind, range: LockRange) -> CResult<Option<ConflictingLock>> {
+		let mut lock = new_flock(Some(kind), range);
+		unsafe { self.fcntl_with_arg(style.get_command(), &mut lock as *mut libc::flock) }?;
+		let kind = match lock.l_type as libc::c_int {
+			libc::F_UNLCK => return Ok(None),
+			libc::F_RDLCK => LockKind::Shared,
+			_ => LockKind::Exclusive,
+		};
+		Ok(Some(ConflictingLock {
+			kind,
+			range: LockRange {
+				start: lock.l_start,
+				length: lock.l_len,
+			},
+			pid: lock.l_pid,
+		}))
+	}
+
+	pub fn lock(&self, kind: LockKind, range: LockRange) -> CResult<RecordLockGuard<'_>> {
+		self.record_lock(RecordLockStyle::Posix, kind, range)
+	}
+
+	pub fn try_lock(&self, kind: LockKind, range: LockRange) -> CResult<Option<RecordLockGuard<'_>>> {
+		self.try_record_lock(RecordLockStyle::Posix, kind, range)
+	}
+
+	pub fn lock_timeout(&self, kind: LockKind, range: LockRange, timeout: Duration) -> CResult<Option<RecordLockGuard<'_>>> {
+		retry_until(timeout, || self.try_lock(kind, range))
+	}
+
+	// Returns the first lock that would stop kind from being taken on range
+	pub fn get_lock(&self, kind: LockKind, range: LockRange) -> CResult<Option<ConflictingLock>> {
+		self.get_record_lock(RecordLockStyle::Posix, kind, range)
+	}
+}
+
+// Open file description locks, owned by the open file rather than the process
+#[cfg(target_os = "linux")]
+impl FileDescriptor {
+	pub fn ofd_lock(&self, kind: LockKind, range: LockRange) -> CResult<RecordLockGuard<'_>> {
+		self.record_lock(RecordLockStyle::OpenFileDescription, kind, range)
+	}
+
+	pub fn try_ofd_lock(&self, kind: LockKind, range: LockRange) -> CResult<Option<RecordLockGuard<'_>>> {
+		self.try_record_lock(RecordLockStyle::OpenFileDescription, kind, range)
+	}
+
+	pub fn ofd_lock_timeout(&self, kind: LockKind, range: LockRange, timeout: Duration) -> CResult<Option<RecordLockGuard<'_>>> {
+		retry_until(timeout, || self.try_ofd_lock(kind, range))
+	}
+
+	pub fn get_ofd_lock(&self, kind: LockKind, range: LockRange) -> CResult<Option<ConflictingLock>> {
+		self.get_record_lock(RecordLockStyle::OpenFileDescription, kind, range)
+	}
+}
@@ -0,0 +1,94 @@
+use std::ffi::CString;
+
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult};
+
+use super::FileDescriptor;
+
+bitflags! {
+	pub struct MemfdFlags: libc::c_uint {
+		const CLOEXEC = libc::MFD_CLOEXEC;
+		// Without it, the file starts out with SEAL_SEAL and can't be sealed
+		const ALLOW_SEALING = libc::MFD_ALLOW_SEALING;
+		// Back the file with huge pages; the page size defaults to the
+		// system's, or can be picked with one of the HUGE_ flags
+		const HUGETLB = libc::MFD_HUGETLB;
+		const HUGE_2MB = libc::MFD_HUGE_2MB;
+		const HUGE_1GB = libc::MFD_HUGE_1GB;
+		// The file can never be made executable; implies ALLOW_SEALING.
+		// Needs Linux 6.3 or later
+		const NOEXEC_SEAL = libc::MFD_NOEXEC_SEAL;
+		const EXEC = libc::MFD_EXEC;
+	}
+}
+
+bitflags! {
+	// Seals restrict what can be done to a file through any descriptor or
+	// mapping, and can't be removed once added
+	pub struct Seals: libc::c_int {
+		// No more seals can be added
+		const SEAL_SEAL = libc::F_SEAL_SEAL;
+		const SEAL_SHRINK = libc::F_SEAL_SHRINK;
+		const SEAL_GROW = libc::F_SEAL_GROW;
+		// Fails with Busy while a writable shared mapping exists
+		const SEAL_WRITE = libc::F_SEAL_WRITE;
+		// Like SEAL_WRITE, but existing writable mappings keep working
+		const SEAL_FUTURE_WRITE = libc::F_SEAL_FUTURE_WRITE;
+		const SEAL_EXEC = libc::F_SEAL_EXEC;
+	}
+}
+
+// Creates an anonymous file that lives in memory. The name is only shown in
+// /proc/self/fd and doesn't need to be unique
+pub fn memfd_create<Name: Into<CString>>(name: Name, flags: MemfdFlags) -> CResult<FileDescriptor> {
+	let name: CString = name.into();
+	match unsafe { libc::memfd_create(name.as_ptr(), flags.bits()) } {
+		-1 => Err(CError::new_from_errno()),
+		fd => Ok(unsafe { FileDescriptor::from_unowned(fd) }),
+	}
+}
+
+impl FileDescriptor {
+	// Fails with Perm if SEAL_SEAL is set, and with Invalid for
+	// files that don't support sealing
+	pub fn add_seals(&self, seals: Seals) -> CResult<()> {
+		unsafe { self.fcntl_with_arg(libc::F_ADD_SEALS, seals.bits()) }.map(|_| ())
+	}
+
+	pub fn seals(&self) -> CResult<Seals> {
+		let seals = unsafe { self.fcntl(libc::F_GET_SEALS) }?;
+		Ok(Seals::from_bits_truncate(seals))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sealable() -> FileDescriptor {
+		memfd_create(CString::new("memfd-test").unwrap(), MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING).unwrap()
+	}
+
+	#[test]
+	fn writes_fail_once_sealed() {
+		let mut fd = sealable();
+		fd.write_slice(b"before").unwrap();
+		fd.add_seals(Seals::SEAL_WRITE).unwrap();
+		assert!(fd.seals().unwrap().contains(Seals::SEAL_WRITE));
+		assert!(matches!(fd.write_slice(b"after"), Err(CError::Perm)));
+	}
+
+	#[test]
+	fn seal_seal_refuses_further_seals() {
+		let fd = sealable();
+		fd.add_seals(Seals::SEAL_SEAL).unwrap();
+		assert!(matches!(fd.add_seals(Seals::SEAL_WRITE), Err(CError::Perm)));
+	}
+
+	#[test]
+	fn files_start_sealed_without_allow_sealing() {
+		let fd = memfd_create(CString::new("memfd-test").unwrap(), MemfdFlags::CLOEXEC).unwrap();
+		assert!(fd.seals().unwrap().contains(Seals::SEAL_SEAL));
+	}
+}
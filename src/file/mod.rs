@@ -1,5 +1,18 @@
 pub mod open;
 mod descriptor;
+mod borrowed;
+pub use borrowed::*;
 pub use descriptor::*;
 pub mod chmod;
 pub mod access;
+pub mod termios;
+pub mod fcntl;
+pub mod lock;
+pub mod seek;
+pub mod vectored;
+pub mod stat;
+pub mod dir;
+pub mod sandbox;
+pub mod transfer;
+#[cfg(target_os = "linux")]
+pub mod memfd;
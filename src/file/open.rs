@@ -1,5 +1,7 @@
-use std::ffi::CString;
+use std::{ffi::CString, os::unix::io::{AsFd, AsRawFd}};
 
+#[cfg(target_os = "linux")]
+use bitflags::bitflags;
 use libc::{c_int, mode_t};
 
 use crate::{c_error::CError, c_result::CResult};
@@ -38,26 +40,73 @@ pub fn creat<Path: Into<CString>>(pathname: Path, mode: mode_t) -> CResult<FileD
 	}
 }
 
-pub fn openat<Path: Into<CString>>(dir: &FileDescriptor, pathname: Path) -> CResult<FileDescriptor> {
+pub fn openat<Dir: AsFd, Path: Into<CString>>(dir: Dir, pathname: Path) -> CResult<FileDescriptor> {
 	openat_with_flags(dir, pathname, 0)
 }
 
-pub fn openat_with_flags<Path: Into<CString>>(dir: &FileDescriptor, pathname: Path, flags: c_int) -> CResult<FileDescriptor> {
+pub fn openat_with_flags<Dir: AsFd, Path: Into<CString>>(dir: Dir, pathname: Path, flags: c_int) -> CResult<FileDescriptor> {
 	let pathname: CString = pathname.into();
-	match unsafe { libc::openat(dir.fd, pathname.as_ptr(), flags) } {
+	match unsafe { libc::openat(dir.as_fd().as_raw_fd(), pathname.as_ptr(), flags) } {
 		-1 => Err(CError::new_from_errno()),
 		fd => Ok(unsafe { FileDescriptor::from_unowned(fd) }),
 	}
 }
 
-pub fn openat_with_mode<Path: Into<CString>>(dir: &FileDescriptor, pathname: Path, flags: c_int, mode: mode_t) -> CResult<FileDescriptor> {
+pub fn openat_with_mode<Dir: AsFd, Path: Into<CString>>(dir: Dir, pathname: Path, flags: c_int, mode: mode_t) -> CResult<FileDescriptor> {
 	let pathname: CString = pathname.into();
-	match unsafe { libc::openat(dir.fd, pathname.as_ptr(), flags, mode as libc::c_uint) } {
+	match unsafe { libc::openat(dir.as_fd().as_raw_fd(), pathname.as_ptr(), flags, mode as libc::c_uint) } {
 		-1 => Err(CError::new_from_errno()),
 		fd => Ok(unsafe { FileDescriptor::from_unowned(fd) }),
 	}
 }
 
+#[cfg(target_os = "linux")]
+bitflags! {
+	// How openat2 may resolve each component of the path
+	pub struct ResolveFlags: u64 {
+		// Fail with CrossDevice if any component, .. or a symlink, would
+		// leave the directory
+		const BENEATH = libc::RESOLVE_BENEATH;
+		// Treat the directory as the root, clamping .. and absolute symlinks
+		// to it, like chroot
+		const IN_ROOT = libc::RESOLVE_IN_ROOT;
+		const NO_SYMLINKS = libc::RESOLVE_NO_SYMLINKS;
+		// Refuse /proc/[pid]/fd style links
+		const NO_MAGICLINKS = libc::RESOLVE_NO_MAGICLINKS;
+		// Refuse to cross a mount point
+		const NO_XDEV = libc::RESOLVE_NO_XDEV;
+		// Only resolve from the dentry cache, failing with Again otherwise
+		const CACHED = libc::RESOLVE_CACHED;
+	}
+}
+
+// Fails with NoSys on kernels older than 5.6
+#[cfg(target_os = "linux")]
+pub fn openat2<Dir: AsFd, Path: Into<CString>>(dir: Dir, pathname: Path, flags: c_int, mode: mode_t, resolve: ResolveFlags) -> CResult<FileDescriptor> {
+	let pathname: CString = pathname.into();
+	let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+	how.flags = flags as u64;
+	// openat2 rejects a mode unless the file may be created; O_TMPFILE
+	// includes the O_DIRECTORY bit, so all of its bits must be checked
+	if flags & libc::O_CREAT != 0 || flags & libc::O_TMPFILE == libc::O_TMPFILE {
+		how.mode = mode as u64;
+	}
+	how.resolve = resolve.bits();
+	let fd = unsafe {
+		libc::syscall(
+			libc::SYS_openat2,
+			dir.as_fd().as_raw_fd(),
+			pathname.as_ptr(),
+			&how as *const libc::open_how,
+			std::mem::size_of::<libc::open_how>(),
+		)
+	};
+	match fd {
+		-1 => Err(CError::new_from_errno()),
+		fd => Ok(unsafe { FileDescriptor::from_unowned(fd as c_int) }),
+	}
+}
+
 pub mod flags {
 	pub use libc::{
 		O_RDONLY,
@@ -87,3 +136,15 @@ pub mod flags {
 		O_LARGEFILE,
 	};
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use super::*;
+	use crate::file::dir::Dir;
+
+	#[test]
+	fn openat2_ignores_the_mode_when_nothing_is_created() {
+		let root = Dir::from_path(CString::new("/").unwrap()).unwrap();
+		openat2(&root, CString::new("tmp").unwrap(), flags::O_RDONLY | flags::O_DIRECTORY, 0o755, ResolveFlags::BENEATH).unwrap();
+	}
+}
@@ -0,0 +1,336 @@
+use std::ffi::{CStr, CString};
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{c_error::CError, c_result::CResult};
+
+#[cfg(target_os = "linux")]
+use super::open::{flags::{O_CLOEXEC, O_PATH}, openat2, ResolveFlags};
+use super::{
+	dir::{flags::AT_SYMLINK_NOFOLLOW, readlinkat, Dir, ReadDir},
+	open::{flags::{O_DIRECTORY, O_NOFOLLOW, O_RDONLY}, openat_with_flags, openat_with_mode},
+	stat::Metadata,
+	FileDescriptor,
+};
+
+// The same limit the kernel uses
+static MAX_SYMLINKS: usize = 40;
+
+// Cleared the first time openat2 turns out to be missing, or to be refused
+// outright, as by seccomp profiles that predate it
+#[cfg(target_os = "linux")]
+static OPENAT2_SUPPORTED: AtomicBool = AtomicBool::new(true);
+
+// A directory that no path given to its methods can escape, whether through
+// .., absolute paths or symlinks; any attempt fails with CrossDevice
+pub struct SandboxDir {
+	root: Dir,
+}
+
+impl SandboxDir {
+	pub fn new(root: Dir) -> Self {
+		Self {
+			root,
+		}
+	}
+
+	pub fn from_path<Path: Into<CString>>(pathname: Path) -> CResult<Self> {
+		Ok(Self::new(Dir::from_path(pathname)?))
+	}
+
+	pub fn root(&self) -> &Dir {
+		&self.root
+	}
+
+	pub fn into_root(self) -> Dir {
+		self.root
+	}
+
+	pub fn open<Path: Into<CString>>(&self, pathname: Path, flags: libc::c_int) -> CResult<FileDescriptor> {
+		self.resolve(&pathname.into(), flags, 0)
+	}
+
+	pub fn open_with_mode<Path: Into<CString>>(&self, pathname: Path, flags: libc::c_int, mode: libc::mode_t) -> CResult<FileDescriptor> {
+		self.resolve(&pathname.into(), flags, mode)
+	}
+
+	// The returned sandbox is confined to the subdirectory
+	pub fn open_dir<Path: Into<CString>>(&self, pathname: Path) -> CResult<SandboxDir> {
+		let fd = self.resolve(&pathname.into(), O_RDONLY | O_DIRECTORY, 0)?;
+		Ok(Self::new(Dir::from_fd(fd)))
+	}
+
+	pub fn read_dir<Path: Into<CString>>(&self, pathname: Path) -> CResult<ReadDir> {
+		ReadDir::from_fd(self.resolve(&pathname.into(), O_RDONLY | O_DIRECTORY, 0)?)
+	}
+
+	pub fn mkdir<Path: Into<CString>>(&self, pathname: Path, mode: libc::mode_t) -> CResult<()> {
+		let (parent, name) = self.parent(&pathname.into())?;
+		parent.mkdir(name, mode)
+	}
+
+	pub fn unlink<Path: Into<CString>>(&self, pathname: Path) -> CResult<()> {
+		let (parent, name) = self.parent(&pathname.into())?;
+		parent.unlink(name)
+	}
+
+	pub fn rmdir<Path: Into<CString>>(&self, pathname: Path) -> CResult<()> {
+		let (parent, name) = self.parent(&pathname.into())?;
+		parent.rmdir(name)
+	}
+
+	pub fn rename<Old: Into<CString>, New: Into<CString>>(&self, old_pathname: Old, new_pathname: New) -> CResult<()> {
+		let (old_parent, old_name) = self.parent(&old_pathname.into())?;
+		let (new_parent, new_name) = self.parent(&new_pathname.into())?;
+		old_parent.rename_to(old_name, &new_parent, new_name)
+	}
+
+	// A symlink to old_pathname is not followed
+	pub fn link<Old: Into<CString>, New: Into<CString>>(&self, old_pathname: Old, new_pathname: New) -> CResult<()> {
+		let (old_parent, old_name) = self.parent(&old_pathname.into())?;
+		let (new_parent, new_name) = self.parent(&new_pathname.into())?;
+		old_parent.link_to(old_name, &new_parent, new_name, 0)
+	}
+
+	// The target may point anywhere, but is still confined when followed
+	// through this sandbox
+	pub fn symlink<Target: Into<CString>, Path: Into<CString>>(&self, target: Target, link_pathname: Path) -> CResult<()> {
+		let (parent, name) = self.parent(&link_pathname.into())?;
+		parent.symlink(target, name)
+	}
+
+	pub fn readlink<Path: Into<CString>>(&self, pathname: Path) -> CResult<CString> {
+		let (parent, name) = self.parent(&pathname.into())?;
+		parent.readlink(name)
+	}
+
+	// Describes a final symlink itself, like lstat
+	pub fn stat<Path: Into<CString>>(&self, pathname: Path) -> CResult<Metadata> {
+		let pathname: CString = pathname.into();
+		match split_parent(pathname.as_bytes()) {
+			Some(_) => {
+				let (parent, name) = self.parent(&pathname)?;
+				parent.stat(name, AT_SYMLINK_NOFOLLOW)
+			},
+			// ., .. and the empty path name directories, which can be opened
+			None => self.resolve(&pathname, O_RDONLY | O_DIRECTORY, 0)?.fstat(),
+		}
+	}
+
+	// Opens the directory containing the last component of pathname, which
+	// must be a plain name
+	fn parent(&self, pathname: &CStr) -> CResult<(Dir, CString)> {
+		let (parent, name) = split_parent(pathname.to_bytes()).ok_or(CError::Invalid)?;
+		let parent = match parent {
+			b"" => CString::new(".").unwrap(),
+			parent => CString::new(parent).unwrap(),
+		};
+		let parent = self.resolve(&parent, O_RDONLY | O_DIRECTORY, 0)?;
+		Ok((Dir::from_fd(parent), CString::new(name).unwrap()))
+	}
+
+	// The empty path names the root, as it does for the other methods
+	fn resolve(&self, pathname: &CStr, flags: libc::c_int, mode: libc::mode_t) -> CResult<FileDescriptor> {
+		let dot = CString::new(".").unwrap();
+		let pathname = match pathname.to_bytes() {
+			b"" => dot.as_c_str(),
+			_ => pathname,
+		};
+		#[cfg(target_os = "linux")]
+		if OPENAT2_SUPPORTED.load(Ordering::Relaxed) {
+			let resolve = ResolveFlags::BENEATH | ResolveFlags::NO_MAGICLINKS;
+			match openat2(&self.root, pathname.to_owned(), flags, mode, resolve) {
+				Err(CError::NoSys) => OPENAT2_SUPPORTED.store(false, Ordering::Relaxed),
+				Err(CError::Perm) if self.openat2_refused() => OPENAT2_SUPPORTED.store(false, Ordering::Relaxed),
+				result => return result,
+			}
+		}
+		self.resolve_by_component(pathname, flags, mode)
+	}
+
+	// EPERM can also come from the path given, such as a file that can't be
+	// written or opened with O_NOATIME, so it only means openat2 itself is
+	// refused if opening the root with O_PATH, which can't fail that way,
+	// gets it too
+	#[cfg(target_os = "linux")]
+	fn openat2_refused(&self) -> bool {
+		let dot = CString::new(".").unwrap();
+		matches!(openat2(&self.root, dot, O_PATH | O_CLOEXEC, 0, ResolveFlags::BENEATH), Err(CError::Perm))
+	}
+
+	// Walks the path one component at a time, never letting the kernel follow
+	// a symlink, and tracks the directories entered so .. can't go above the
+	// root
+	fn resolve_by_component(&self, pathname: &CStr, flags: libc::c_int, mode: libc::mode_t) -> CResult<FileDescriptor> {
+		let mut pending: Vec<Vec<u8>> = Vec::new();
+		push_components(&mut pending, pathname.to_bytes())?;
+		let mut entered: Vec<FileDescriptor> = Vec::new();
+		let mut symlinks_followed = 0;
+
+		loop {
+			let current = match entered.last() {
+				Some(fd) => fd,
+				None => self.root.fd(),
+			};
+			let component = match pending.pop() {
+				Some(component) => component,
+				// The path ended on the directory itself, as with "" or "a/.."
+				None => return openat_with_mode(current, CString::new(".").unwrap(), flags, mode),
+			};
+			let last = pending.is_empty();
+			match component.as_slice() {
+				b"." => {},
+				b".." => {
+					entered.pop().ok_or(CError::CrossDevice)?;
+				},
+				_ => {
+					let name = CString::new(component).unwrap();
+					let opened = if last {
+						openat_with_mode(current, name.clone(), flags | O_NOFOLLOW, mode)
+					}
+					else {
+						openat_with_flags(current, name.clone(), O_RDONLY | O_DIRECTORY | O_NOFOLLOW)
+					};
+					match opened {
+						Ok(fd) if last => return Ok(fd),
+						Ok(fd) => entered.push(fd),
+						// O_NOFOLLOW gives TooManySymlinks for a final symlink, and
+						// O_DIRECTORY gives NotADirectory for an intermediate one
+						Err(err @ CError::TooManySymlinks) | Err(err @ CError::NotADirectory) => {
+							if last && flags & O_NOFOLLOW != 0 {
+								return Err(err);
+							}
+							// Anything readlink fails on is not a symlink
+							let target = match readlinkat(current.fd, &name) {
+								Ok(target) => target,
+								Err(_) => return Err(err),
+							};
+							symlinks_followed += 1;
+							if symlinks_followed > MAX_SYMLINKS {
+								return Err(CError::TooManySymlinks);
+							}
+							push_components(&mut pending, target.as_bytes())?;
+						},
+						Err(err) => return Err(err),
+					}
+				},
+			}
+		}
+	}
+}
+
+// Pushes the components of path so the first one is popped first; absolute
+// paths would start over at the real root, so they are refused
+fn push_components(pending: &mut Vec<Vec<u8>>, path: &[u8]) -> CResult<()> {
+	if path.starts_with(b"/") {
+		return Err(CError::CrossDevice);
+	}
+	pending.extend(path.split(|&byte| byte == b'/').filter(|component| !component.is_empty()).rev().map(|component| component.to_vec()));
+	Ok(())
+}
+
+// Splits off the last component, unless it is ., .. or missing
+fn split_parent(path: &[u8]) -> Option<(&[u8], &[u8])> {
+	let mut end = path.len();
+	while end > 1 && path[end - 1] == b'/' {
+		end -= 1;
+	}
+	let path = &path[..end];
+	let (parent, name) = match path.iter().rposition(|&byte| byte == b'/') {
+		Some(0) => (&path[..1], &path[1..]),
+		Some(slash) => (&path[..slash], &path[slash + 1..]),
+		None => (&b""[..], path),
+	};
+	match name {
+		b"" | b"." | b".." => None,
+		name => Some((parent, name)),
+	}
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use super::*;
+	use crate::{fork::{fork, ForkResult}, wait::waitpid};
+
+	// Makes openat2 fail with errno in the calling thread only
+	fn refuse_openat2(errno: libc::c_int) {
+		let mut filter = [
+			libc::sock_filter { code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16, jt: 0, jf: 0, k: 0 },
+			libc::sock_filter { code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16, jt: 0, jf: 1, k: libc::SYS_openat2 as u32 },
+			libc::sock_filter { code: (libc::BPF_RET | libc::BPF_K) as u16, jt: 0, jf: 0, k: libc::SECCOMP_RET_ERRNO | errno as u32 },
+			libc::sock_filter { code: (libc::BPF_RET | libc::BPF_K) as u16, jt: 0, jf: 0, k: libc::SECCOMP_RET_ALLOW },
+		];
+		let program = libc::sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+		unsafe {
+			assert_eq!(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0), 0);
+			assert_eq!(libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program as *const libc::sock_fprog), 0);
+		}
+	}
+
+	fn falls_back_when_openat2_fails_with(errno: libc::c_int) {
+		let root = std::env::temp_dir().join(format!("c_wrapper-sandbox-{}-{}", std::process::id(), errno));
+		std::fs::create_dir_all(root.join("inside")).unwrap();
+		std::fs::write(root.join("inside/file"), b"").unwrap();
+		std::os::unix::fs::symlink("/", root.join("inside/escape")).unwrap();
+
+		// In a process of its own, so the rest of the tests keep using openat2
+		let child = match fork().unwrap() {
+			ForkResult::Child => {
+				refuse_openat2(errno);
+				let sandbox = SandboxDir::from_path(CString::new(root.join("inside").to_str().unwrap()).unwrap()).unwrap();
+				let opened = sandbox.open(CString::new("file").unwrap(), O_RDONLY).is_ok();
+				let parent_refused = matches!(sandbox.open(CString::new("../inside/file").unwrap(), O_RDONLY), Err(CError::CrossDevice));
+				let symlink_refused = matches!(sandbox.open(CString::new("escape/etc").unwrap(), O_RDONLY), Err(CError::CrossDevice));
+				let fell_back = !OPENAT2_SUPPORTED.load(Ordering::Relaxed);
+				unsafe { libc::_exit(if opened && parent_refused && symlink_refused && fell_back { 0 } else { 1 }) }
+			},
+			ForkResult::Parent(pid) => pid,
+		};
+		let result = waitpid(child).unwrap();
+		std::fs::remove_dir_all(&root).unwrap();
+		assert!(result.status.exited_normally());
+		assert_eq!(result.status.exit_status(), 0);
+	}
+
+	#[test]
+	fn a_refused_path_keeps_openat2_in_use() {
+		// Root may open any file with O_NOATIME, so the test drops to nobody
+		if unsafe { libc::getuid() } != 0 {
+			return;
+		}
+		let root = std::env::temp_dir().join(format!("c_wrapper-sandbox-{}-noatime", std::process::id()));
+		std::fs::create_dir_all(&root).unwrap();
+		std::fs::write(root.join("file"), b"").unwrap();
+
+		let child = match fork().unwrap() {
+			ForkResult::Child => {
+				let sandbox = SandboxDir::from_path(CString::new(root.to_str().unwrap()).unwrap()).unwrap();
+				let dropped = unsafe { libc::setuid(65534) } == 0;
+				// Only the owner of a file may open it with O_NOATIME
+				let refused = matches!(sandbox.open(CString::new("file").unwrap(), O_RDONLY | libc::O_NOATIME), Err(CError::Perm));
+				let still_used = OPENAT2_SUPPORTED.load(Ordering::Relaxed);
+				unsafe { libc::_exit(if dropped && refused && still_used { 0 } else { 1 }) }
+			},
+			ForkResult::Parent(pid) => pid,
+		};
+		let result = waitpid(child).unwrap();
+		std::fs::remove_dir_all(&root).unwrap();
+		assert!(result.status.exited_normally());
+		assert_eq!(result.status.exit_status(), 0);
+	}
+
+	#[test]
+	fn missing_openat2_is_errno_nosys() {
+		assert!(matches!(CError::from(libc::ENOSYS), CError::NoSys));
+	}
+
+	#[test]
+	fn falls_back_without_openat2() {
+		falls_back_when_openat2_fails_with(libc::ENOSYS);
+	}
+
+	#[test]
+	fn falls_back_when_seccomp_refuses_openat2() {
+		falls_back_when_openat2_fails_with(libc::EPERM);
+	}
+}
@@ -0,0 +1,180 @@
+use std::io::{Error, SeekFrom};
+
+#[cfg(target_os = "linux")]
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult};
+
+use super::FileDescriptor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+	Start,
+	Current,
+	End,
+	// The next offset at or after the given one that contains data
+	#[cfg(target_os = "linux")]
+	Data,
+	// The next offset at or after the given one that is in a hole
+	#[cfg(target_os = "linux")]
+	Hole,
+}
+
+impl From<Whence> for libc::c_int {
+	fn from(whence: Whence) -> Self {
+		match whence {
+			Whence::Start => libc::SEEK_SET,
+			Whence::Current => libc::SEEK_CUR,
+			Whence::End => libc::SEEK_END,
+			#[cfg(target_os = "linux")]
+			Whence::Data => libc::SEEK_DATA,
+			#[cfg(target_os = "linux")]
+			Whence::Hole => libc::SEEK_HOLE,
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+bitflags! {
+	// Per-call flags for preadv2 and pwritev2
+	pub struct ReadWriteFlags: libc::c_int {
+		const HIPRI = libc::RWF_HIPRI;
+		const DSYNC = libc::RWF_DSYNC;
+		const SYNC = libc::RWF_SYNC;
+		// Fail with Again instead of waiting for data that isn't cached
+		const NOWAIT = libc::RWF_NOWAIT;
+		// Write at the end of the file, ignoring the offset
+		const APPEND = libc::RWF_APPEND;
+	}
+}
+
+impl FileDescriptor {
+	pub fn lseek(&self, offset: libc::off_t, whence: Whence) -> CResult<libc::off_t> {
+		match unsafe { libc::lseek(self.fd, offset, whence.into()) } {
+			-1 => Err(CError::new_from_errno()),
+			position => Ok(position),
+		}
+	}
+
+	pub fn position(&self) -> CResult<libc::off_t> {
+		self.lseek(0, Whence::Current)
+	}
+
+	// Returns None if there is no data after offset
+	#[cfg(target_os = "linux")]
+	pub fn seek_data(&self, offset: libc::off_t) -> CResult<Option<libc::off_t>> {
+		match self.lseek(offset, Whence::Data) {
+			Ok(position) => Ok(Some(position)),
+			Err(CError::NoDeviceOrAddress) => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+
+	// The end of the file counts as a hole, so this only returns None if
+	// offset is past the end
+	#[cfg(target_os = "linux")]
+	pub fn seek_hole(&self, offset: libc::off_t) -> CResult<Option<libc::off_t>> {
+		match self.lseek(offset, Whence::Hole) {
+			Ok(position) => Ok(Some(position)),
+			Err(CError::NoDeviceOrAddress) => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+
+	// Reads at offset without moving the file position
+	pub fn read_at(&self, buf: &mut [u8], offset: libc::off_t) -> CResult<usize> {
+		match unsafe { libc::pread(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), offset) } {
+			-1 => Err(CError::new_from_errno()),
+			bytes_read => Ok(bytes_read as usize),
+		}
+	}
+
+	// Writes at offset without moving the file position
+	pub fn write_at(&self, buf: &[u8], offset: libc::off_t) -> CResult<usize> {
+		match unsafe { libc::pwrite(self.fd, buf.as_ptr() as *const libc::c_void, buf.len(), offset) } {
+			-1 => Err(CError::new_from_errno()),
+			bytes_written => Ok(bytes_written as usize),
+		}
+	}
+
+	// Fails with IO if the file ends before buf is filled
+	pub fn read_exact_at(&self, mut buf: &mut [u8], mut offset: libc::off_t) -> CResult<()> {
+		while !buf.is_empty() {
+			match self.read_at(buf, offset) {
+				Ok(0) => return Err(CError::IO),
+				Ok(bytes_read) => {
+					buf = &mut buf[bytes_read..];
+					offset += bytes_read as libc::off_t;
+				},
+				Err(CError::Interrupted) => {},
+				Err(err) => return Err(err),
+			}
+		}
+		Ok(())
+	}
+
+	pub fn write_all_at(&self, mut buf: &[u8], mut offset: libc::off_t) -> CResult<()> {
+		while !buf.is_empty() {
+			match self.write_at(buf, offset) {
+				Ok(0) => return Err(CError::IO),
+				Ok(bytes_written) => {
+					buf = &buf[bytes_written..];
+					offset += bytes_written as libc::off_t;
+				},
+				Err(CError::Interrupted) => {},
+				Err(err) => return Err(err),
+			}
+		}
+		Ok(())
+	}
+
+	// An offset of -1 uses and updates the file position, like read
+	#[cfg(target_os = "linux")]
+	pub fn read_at_with_flags(&self, buf: &mut [u8], offset: libc::off_t, flags: ReadWriteFlags) -> CResult<usize> {
+		let iov = libc::iovec {
+			iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+			iov_len: buf.len(),
+		};
+		match unsafe { libc::preadv2(self.fd, &iov, 1, offset, flags.bits()) } {
+			-1 => Err(CError::new_from_errno()),
+			bytes_read => Ok(bytes_read as usize),
+		}
+	}
+
+	// An offset of -1 uses and updates the file position, like write
+	#[cfg(target_os = "linux")]
+	pub fn write_at_with_flags(&self, buf: &[u8], offset: libc::off_t, flags: ReadWriteFlags) -> CResult<usize> {
+		let iov = libc::iovec {
+			iov_base: buf.as_ptr() as *mut libc::c_void,
+			iov_len: buf.len(),
+		};
+		match unsafe { libc::pwritev2(self.fd, &iov, 1, offset, flags.bits()) } {
+			-1 => Err(CError::new_from_errno()),
+			bytes_written => Ok(bytes_written as usize),
+		}
+	}
+}
+
+fn seek_fd(fd: &FileDescriptor, position: SeekFrom) -> std::io::Result<u64> {
+	let (offset, whence) = match position {
+		SeekFrom::Start(offset) => (offset as libc::off_t, Whence::Start),
+		SeekFrom::Current(offset) => (offset as libc::off_t, Whence::Current),
+		SeekFrom::End(offset) => (offset as libc::off_t, Whence::End),
+	};
+	match fd.lseek(offset, whence) {
+		Ok(position) => Ok(position as u64),
+		Err(err) => Err(Error::from_raw_os_error(err.into())),
+	}
+}
+
+impl std::io::Seek for FileDescriptor {
+	fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+		seek_fd(self, position)
+	}
+}
+
+impl std::io::Seek for &FileDescriptor {
+	fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+		seek_fd(self, position)
+	}
+}
@@ -0,0 +1,283 @@
+use std::{ffi::CString, os::unix::io::{AsFd, AsRawFd}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+#[cfg(target_os = "linux")]
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult};
+
+use super::FileDescriptor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+	Regular,
+	Directory,
+	Symlink,
+	Fifo,
+	Socket,
+	CharacterDevice,
+	BlockDevice,
+	// A character device that is a terminal; only FileDescriptor::file_type
+	// checks for this, since it needs an open descriptor
+	Terminal,
+	Unknown,
+}
+
+impl From<libc::mode_t> for FileType {
+	fn from(mode: libc::mode_t) -> Self {
+		match mode & libc::S_IFMT {
+			libc::S_IFREG => Self::Regular,
+			libc::S_IFDIR => Self::Directory,
+			libc::S_IFLNK => Self::Symlink,
+			libc::S_IFIFO => Self::Fifo,
+			libc::S_IFSOCK => Self::Socket,
+			libc::S_IFCHR => Self::CharacterDevice,
+			libc::S_IFBLK => Self::BlockDevice,
+			_ => Self::Unknown,
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+bitflags! {
+	// Which fields statx should fill in; the kernel may return more or fewer
+	pub struct StatxMask: libc::c_uint {
+		const TYPE = libc::STATX_TYPE;
+		const MODE = libc::STATX_MODE;
+		const NLINK = libc::STATX_NLINK;
+		const UID = libc::STATX_UID;
+		const GID = libc::STATX_GID;
+		const ATIME = libc::STATX_ATIME;
+		const MTIME = libc::STATX_MTIME;
+		const CTIME = libc::STATX_CTIME;
+		const INO = libc::STATX_INO;
+		const SIZE = libc::STATX_SIZE;
+		const BLOCKS = libc::STATX_BLOCKS;
+		const BASIC_STATS = libc::STATX_BASIC_STATS;
+		const BTIME = libc::STATX_BTIME;
+		const MNT_ID = libc::STATX_MNT_ID;
+	}
+}
+
+#[cfg(target_os = "linux")]
+bitflags! {
+	pub struct StatxAttributes: u64 {
+		const COMPRESSED = libc::STATX_ATTR_COMPRESSED as u64;
+		const IMMUTABLE = libc::STATX_ATTR_IMMUTABLE as u64;
+		const APPEND = libc::STATX_ATTR_APPEND as u64;
+		const NODUMP = libc::STATX_ATTR_NODUMP as u64;
+		const ENCRYPTED = libc::STATX_ATTR_ENCRYPTED as u64;
+		const AUTOMOUNT = libc::STATX_ATTR_AUTOMOUNT as u64;
+		const MOUNT_ROOT = libc::STATX_ATTR_MOUNT_ROOT as u64;
+		const VERITY = libc::STATX_ATTR_VERITY as u64;
+		const DAX = libc::STATX_ATTR_DAX as u64;
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+	pub file_type: FileType,
+	// Permission bits, including setuid, setgid and sticky
+	pub mode: libc::mode_t,
+	pub uid: libc::uid_t,
+	pub gid: libc::gid_t,
+	pub size: u64,
+	// In 512-byte units
+	pub blocks: u64,
+	pub block_size: u64,
+	pub accessed: SystemTime,
+	pub modified: SystemTime,
+	pub changed: SystemTime,
+	// Only known when filled in by statx
+	pub created: Option<SystemTime>,
+	pub dev: u64,
+	pub ino: u64,
+	pub nlink: u64,
+	pub rdev: u64,
+	pub mount_id: Option<u64>,
+	// Which attributes are set, among the ones the filesystem supports
+	#[cfg(target_os = "linux")]
+	pub attributes: Option<StatxAttributes>,
+	// The fields statx filled in; the others are left as zero. None when
+	// filled in by stat, which fills in all of them
+	#[cfg(target_os = "linux")]
+	pub statx_mask: Option<StatxMask>,
+}
+
+fn system_time(seconds: i64, nanoseconds: i64) -> SystemTime {
+	if seconds >= 0 {
+		UNIX_EPOCH + Duration::new(seconds as u64, nanoseconds as u32)
+	}
+	else {
+		UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs()) + Duration::from_nanos(nanoseconds as u64)
+	}
+}
+
+impl From<libc::stat> for Metadata {
+	// The field types vary between platforms
+	#[allow(clippy::unnecessary_cast)]
+	fn from(stat: libc::stat) -> Self {
+		Self {
+			file_type: FileType::from(stat.st_mode),
+			mode: stat.st_mode & !libc::S_IFMT,
+			uid: stat.st_uid,
+			gid: stat.st_gid,
+			size: stat.st_size as u64,
+			blocks: stat.st_blocks as u64,
+			block_size: stat.st_blksize as u64,
+			accessed: system_time(stat.st_atime as i64, stat.st_atime_nsec as i64),
+			modified: system_time(stat.st_mtime as i64, stat.st_mtime_nsec as i64),
+			changed: system_time(stat.st_ctime as i64, stat.st_ctime_nsec as i64),
+			created: None,
+			dev: stat.st_dev as u64,
+			ino: stat.st_ino as u64,
+			nlink: stat.st_nlink as u64,
+			rdev: stat.st_rdev as u64,
+			mount_id: None,
+			#[cfg(target_os = "linux")]
+			attributes: None,
+			#[cfg(target_os = "linux")]
+			statx_mask: None,
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+impl From<libc::statx> for Metadata {
+	fn from(statx: libc::statx) -> Self {
+		let mask = StatxMask::from_bits_truncate(statx.stx_mask);
+		let time = |timestamp: libc::statx_timestamp| system_time(timestamp.tv_sec, timestamp.tv_nsec as i64);
+		Self {
+			file_type: FileType::from(statx.stx_mode as libc::mode_t),
+			mode: statx.stx_mode as libc::mode_t & !libc::S_IFMT,
+			uid: statx.stx_uid,
+			gid: statx.stx_gid,
+			size: statx.stx_size,
+			blocks: statx.stx_blocks,
+			block_size: statx.stx_blksize as u64,
+			accessed: time(statx.stx_atime),
+			modified: time(statx.stx_mtime),
+			changed: time(statx.stx_ctime),
+			created: if mask.contains(StatxMask::BTIME) { Some(time(statx.stx_btime)) } else { None },
+			dev: libc::makedev(statx.stx_dev_major, statx.stx_dev_minor),
+			ino: statx.stx_ino,
+			nlink: statx.stx_nlink as u64,
+			rdev: libc::makedev(statx.stx_rdev_major, statx.stx_rdev_minor),
+			mount_id: if mask.contains(StatxMask::MNT_ID) { Some(statx.stx_mnt_id) } else { None },
+			attributes: Some(StatxAttributes::from_bits_truncate(statx.stx_attributes & statx.stx_attributes_mask)),
+			statx_mask: Some(mask),
+		}
+	}
+}
+
+// AT_FDCWD is -1, which no AsFd may hold, so the working directory is None
+fn dir_fd<Dir: AsFd>(dir: &Option<Dir>) -> libc::c_int {
+	match dir {
+		Some(dir) => dir.as_fd().as_raw_fd(),
+		None => libc::AT_FDCWD,
+	}
+}
+
+pub fn stat<Path: Into<CString>>(pathname: Path) -> CResult<Metadata> {
+	let pathname: CString = pathname.into();
+	let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+	match unsafe { libc::stat(pathname.as_ptr(), &mut stat) } {
+		0 => Ok(stat.into()),
+		-1 => Err(CError::new_from_errno()),
+		bad_return => panic!("stat returned {}, which is different from 0 or -1", bad_return),
+	}
+}
+
+// Like stat, but describes a symlink itself rather than its target
+pub fn lstat<Path: Into<CString>>(pathname: Path) -> CResult<Metadata> {
+	let pathname: CString = pathname.into();
+	let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+	match unsafe { libc::lstat(pathname.as_ptr(), &mut stat) } {
+		0 => Ok(stat.into()),
+		-1 => Err(CError::new_from_errno()),
+		bad_return => panic!("lstat returned {}, which is different from 0 or -1", bad_return),
+	}
+}
+
+// A dir of None resolves relative path names from the working directory
+pub fn fstatat<Dir: AsFd, Path: Into<CString>>(dir: Option<Dir>, pathname: Path, flags: libc::c_int) -> CResult<Metadata> {
+	let pathname: CString = pathname.into();
+	let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+	match unsafe { libc::fstatat(dir_fd(&dir), pathname.as_ptr(), &mut stat, flags) } {
+		0 => Ok(stat.into()),
+		-1 => Err(CError::new_from_errno()),
+		bad_return => panic!("fstatat returned {}, which is different from 0 or -1", bad_return),
+	}
+}
+
+#[cfg(target_os = "linux")]
+pub fn statx<Dir: AsFd, Path: Into<CString>>(dir: Option<Dir>, pathname: Path, flags: libc::c_int, mask: StatxMask) -> CResult<Metadata> {
+	let pathname: CString = pathname.into();
+	let mut statx: libc::statx = unsafe { std::mem::zeroed() };
+	match unsafe { libc::statx(dir_fd(&dir), pathname.as_ptr(), flags, mask.bits(), &mut statx) } {
+		0 => Ok(statx.into()),
+		-1 => Err(CError::new_from_errno()),
+		bad_return => panic!("statx returned {}, which is different from 0 or -1", bad_return),
+	}
+}
+
+impl FileDescriptor {
+	pub fn fstat(&self) -> CResult<Metadata> {
+		let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+		match unsafe { libc::fstat(self.fd, &mut stat) } {
+			0 => Ok(stat.into()),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("fstat returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn statx(&self, mask: StatxMask) -> CResult<Metadata> {
+		statx(Some(self), CString::default(), flags::AT_EMPTY_PATH, mask)
+	}
+
+	// Pipes are reported as Fifo
+	pub fn file_type(&self) -> CResult<FileType> {
+		match self.fstat()?.file_type {
+			FileType::CharacterDevice if self.is_a_tty() => Ok(FileType::Terminal),
+			file_type => Ok(file_type),
+		}
+	}
+}
+
+pub mod flags {
+	pub use libc::{
+		AT_FDCWD,
+		AT_SYMLINK_NOFOLLOW,
+	};
+
+	#[cfg(target_os = "linux")]
+	pub use libc::{
+		AT_EMPTY_PATH,
+		AT_NO_AUTOMOUNT,
+		AT_STATX_SYNC_AS_STAT,
+		AT_STATX_FORCE_SYNC,
+		AT_STATX_DONT_SYNC,
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fstatat_without_a_dir_is_relative_to_the_working_directory() {
+		let from_cwd = fstatat(None::<&FileDescriptor>, CString::new(".").unwrap(), 0).unwrap();
+		let cwd = stat(CString::new(std::env::current_dir().unwrap().to_str().unwrap()).unwrap()).unwrap();
+		assert_eq!((from_cwd.dev, from_cwd.ino), (cwd.dev, cwd.ino));
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn statx_reports_which_fields_it_filled_in() {
+		let metadata = statx(None::<&FileDescriptor>, CString::new("/").unwrap(), 0, StatxMask::TYPE | StatxMask::INO).unwrap();
+		let filled = metadata.statx_mask.unwrap();
+		assert!(filled.contains(StatxMask::TYPE | StatxMask::INO));
+		assert_eq!(metadata.file_type, FileType::Directory);
+		assert_eq!(stat(CString::new("/").unwrap()).unwrap().statx_mask, None);
+	}
+}
@@ -0,0 +1,260 @@
+use std::ffi::CStr;
+
+use crate::{c_error::CError, c_result::CResult, pty::{get_window_size, WindowSize}};
+
+use super::FileDescriptor;
+
+static TTY_NAME_BUFFER_SIZE: usize = 128;
+
+#[derive(Clone, Copy)]
+pub struct Termios {
+	pub input_flags: libc::tcflag_t,
+	pub output_flags: libc::tcflag_t,
+	pub control_flags: libc::tcflag_t,
+	pub local_flags: libc::tcflag_t,
+	pub control_chars: [libc::cc_t; libc::NCCS],
+	// Keeps the line discipline and, on platforms that store them outside
+	// c_cflag, the speeds; the public fields take precedence over it
+	raw: libc::termios,
+}
+
+impl From<libc::termios> for Termios {
+	fn from(raw: libc::termios) -> Self {
+		Self {
+			input_flags: raw.c_iflag,
+			output_flags: raw.c_oflag,
+			control_flags: raw.c_cflag,
+			local_flags: raw.c_lflag,
+			control_chars: raw.c_cc,
+			raw,
+		}
+	}
+}
+
+impl From<Termios> for libc::termios {
+	fn from(termios: Termios) -> Self {
+		let mut raw = termios.raw;
+		raw.c_iflag = termios.input_flags;
+		raw.c_oflag = termios.output_flags;
+		raw.c_cflag = termios.control_flags;
+		raw.c_lflag = termios.local_flags;
+		raw.c_cc = termios.control_chars;
+		raw
+	}
+}
+
+impl Termios {
+	// Corresponds to cfmakeraw
+	pub fn make_raw(&mut self) {
+		let mut raw: libc::termios = (*self).into();
+		unsafe { libc::cfmakeraw(&mut raw) };
+		*self = raw.into();
+	}
+
+	// Like raw mode, but signals and output processing stay enabled
+	pub fn make_cbreak(&mut self) {
+		self.local_flags &= !(libc::ICANON | libc::ECHO);
+		self.control_chars[libc::VMIN] = 1;
+		self.control_chars[libc::VTIME] = 0;
+	}
+
+	pub fn set_echo(&mut self, echo: bool) {
+		if echo {
+			self.local_flags |= libc::ECHO;
+		}
+		else {
+			self.local_flags &= !libc::ECHO;
+		}
+	}
+
+	pub fn echo(&self) -> bool {
+		self.local_flags & libc::ECHO != 0
+	}
+
+	pub fn input_speed(&self) -> libc::speed_t {
+		let raw: libc::termios = (*self).into();
+		unsafe { libc::cfgetispeed(&raw) }
+	}
+
+	pub fn output_speed(&self) -> libc::speed_t {
+		let raw: libc::termios = (*self).into();
+		unsafe { libc::cfgetospeed(&raw) }
+	}
+
+	// glibc keeps the speed in the CBAUD bits of control_flags, so it goes
+	// through a full conversion rather than only updating raw
+	pub fn set_speed(&mut self, speed: libc::speed_t) -> CResult<()> {
+		let mut raw: libc::termios = (*self).into();
+		match unsafe { libc::cfsetspeed(&mut raw, speed) } {
+			0 => {
+				*self = raw.into();
+				Ok(())
+			},
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("cfsetspeed returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetAttributesWhen {
+	Now,
+	AfterDrain,
+	AfterFlush,
+}
+
+impl From<SetAttributesWhen> for libc::c_int {
+	fn from(when: SetAttributesWhen) -> Self {
+		match when {
+			SetAttributesWhen::Now => libc::TCSANOW,
+			SetAttributesWhen::AfterDrain => libc::TCSADRAIN,
+			SetAttributesWhen::AfterFlush => libc::TCSAFLUSH,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushQueue {
+	Input,
+	Output,
+	Both,
+}
+
+impl From<FlushQueue> for libc::c_int {
+	fn from(queue: FlushQueue) -> Self {
+		match queue {
+			FlushQueue::Input => libc::TCIFLUSH,
+			FlushQueue::Output => libc::TCOFLUSH,
+			FlushQueue::Both => libc::TCIOFLUSH,
+		}
+	}
+}
+
+// Restores the terminal settings it was created with when dropped, including
+// while unwinding from a panic
+pub struct TerminalModeGuard {
+	// A duplicate refers to the same terminal, so the original stays usable
+	fd: FileDescriptor,
+	original: Termios,
+	restored: bool,
+}
+
+impl TerminalModeGuard {
+	pub fn original(&self) -> &Termios {
+		&self.original
+	}
+
+	pub fn restore(mut self) -> CResult<()> {
+		self.restored = true;
+		self.fd.tcsetattr(SetAttributesWhen::AfterDrain, &self.original)
+	}
+}
+
+impl Drop for TerminalModeGuard {
+	fn drop(&mut self) {
+		if !self.restored {
+			let _ = self.fd.tcsetattr(SetAttributesWhen::AfterDrain, &self.original);
+		}
+	}
+}
+
+impl FileDescriptor {
+	pub fn tcgetattr(&self) -> CResult<Termios> {
+		let mut raw: libc::termios = unsafe { std::mem::zeroed() };
+		match unsafe { libc::tcgetattr(self.fd, &mut raw) } {
+			0 => Ok(raw.into()),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("tcgetattr returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+
+	pub fn tcsetattr(&self, when: SetAttributesWhen, termios: &Termios) -> CResult<()> {
+		let raw: libc::termios = (*termios).into();
+		match unsafe { libc::tcsetattr(self.fd, when.into(), &raw) } {
+			0 => Ok(()),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("tcsetattr returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+
+	pub fn tcflush(&self, queue: FlushQueue) -> CResult<()> {
+		match unsafe { libc::tcflush(self.fd, queue.into()) } {
+			0 => Ok(()),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("tcflush returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+
+	pub fn tcdrain(&self) -> CResult<()> {
+		match unsafe { libc::tcdrain(self.fd) } {
+			0 => Ok(()),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("tcdrain returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+
+	// Applies the settings produced by modify until the guard is dropped
+	pub fn with_terminal_mode<Modify: FnOnce(&mut Termios)>(&self, modify: Modify) -> CResult<TerminalModeGuard> {
+		let original = self.tcgetattr()?;
+		let mut modified = original;
+		modify(&mut modified);
+		let guard = TerminalModeGuard {
+			fd: self.try_clone()?,
+			original,
+			restored: false,
+		};
+		self.tcsetattr(SetAttributesWhen::AfterFlush, &modified)?;
+		Ok(guard)
+	}
+
+	pub fn enter_raw_mode(&self) -> CResult<TerminalModeGuard> {
+		self.with_terminal_mode(Termios::make_raw)
+	}
+
+	pub fn enter_cbreak_mode(&self) -> CResult<TerminalModeGuard> {
+		self.with_terminal_mode(Termios::make_cbreak)
+	}
+
+	// Useful while reading passwords
+	pub fn disable_echo(&self) -> CResult<TerminalModeGuard> {
+		self.with_terminal_mode(|termios| termios.set_echo(false))
+	}
+
+	pub fn set_echo(&self, echo: bool) -> CResult<()> {
+		let mut termios = self.tcgetattr()?;
+		termios.set_echo(echo);
+		self.tcsetattr(SetAttributesWhen::Now, &termios)
+	}
+
+	pub fn window_size(&self) -> CResult<WindowSize> {
+		get_window_size(self)
+	}
+
+	pub fn ttyname(&self) -> CResult<String> {
+		let mut buffer = vec![0 as libc::c_char; TTY_NAME_BUFFER_SIZE];
+		match unsafe { libc::ttyname_r(self.fd, buffer.as_mut_ptr(), buffer.len()) } {
+			0 => Ok(unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_string_lossy().into_owned()),
+			errno => Err(CError::from(errno)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pty::openpty;
+
+	#[test]
+	fn speed_survives_a_round_trip_through_the_terminal() {
+		let pty = openpty(None).unwrap();
+		let mut termios = pty.slave.tcgetattr().unwrap();
+		let speed = if termios.output_speed() == libc::B9600 { libc::B19200 } else { libc::B9600 };
+		termios.set_speed(speed).unwrap();
+		assert_eq!(termios.output_speed(), speed);
+		pty.slave.tcsetattr(SetAttributesWhen::Now, &termios).unwrap();
+
+		let termios = pty.slave.tcgetattr().unwrap();
+		assert_eq!(termios.input_speed(), speed);
+		assert_eq!(termios.output_speed(), speed);
+	}
+}
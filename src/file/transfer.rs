@@ -0,0 +1,274 @@
+use std::os::unix::io::{AsFd, AsRawFd};
+#[cfg(target_os = "linux")]
+use std::io::IoSlice;
+
+#[cfg(target_os = "linux")]
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult};
+
+// The most a single sendfile, splice or copy_file_range call moves on Linux
+#[cfg(target_os = "linux")]
+static MAX_CHUNK: usize = 0x7fff_f000;
+static COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+#[cfg(target_os = "linux")]
+bitflags! {
+	pub struct SpliceFlags: libc::c_uint {
+		// A hint to move pages instead of copying them
+		const MOVE = libc::SPLICE_F_MOVE;
+		// Don't block on the pipes, though the other end may still block
+		const NONBLOCK = libc::SPLICE_F_NONBLOCK;
+		// More data will follow, as with TCP_CORK
+		const MORE = libc::SPLICE_F_MORE;
+		// For vmsplice, hand the pages over to the kernel
+		const GIFT = libc::SPLICE_F_GIFT;
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn offset_ptr(offset: Option<&mut libc::off_t>) -> *mut libc::off_t {
+	match offset {
+		Some(offset) => offset,
+		None => std::ptr::null_mut(),
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn transferred(result: libc::ssize_t) -> CResult<usize> {
+	match result {
+		-1 => Err(CError::new_from_errno()),
+		bytes => Ok(bytes as usize),
+	}
+}
+
+// Copies from in_fd, which must support mmap, to any out_fd inside the
+// kernel. With an offset, in_fd's position is left alone and offset is
+// advanced instead
+#[cfg(target_os = "linux")]
+pub fn sendfile<Out: AsFd, In: AsFd>(out_fd: Out, in_fd: In, offset: Option<&mut libc::off_t>, count: usize) -> CResult<usize> {
+	transferred(unsafe {
+		libc::sendfile(out_fd.as_fd().as_raw_fd(), in_fd.as_fd().as_raw_fd(), offset_ptr(offset), count.min(MAX_CHUNK))
+	})
+}
+
+// Moves data to or from a pipe without copying it through userspace; at
+// least one of the descriptors must be a pipe, and pipes take no offset
+#[cfg(target_os = "linux")]
+pub fn splice<In: AsFd, Out: AsFd>(in_fd: In, in_offset: Option<&mut libc::off_t>, out_fd: Out, out_offset: Option<&mut libc::off_t>, length: usize, flags: SpliceFlags) -> CResult<usize> {
+	transferred(unsafe {
+		libc::splice(
+			in_fd.as_fd().as_raw_fd(),
+			offset_ptr(in_offset),
+			out_fd.as_fd().as_raw_fd(),
+			offset_ptr(out_offset),
+			length.min(MAX_CHUNK),
+			flags.bits(),
+		)
+	})
+}
+
+// Duplicates data from one pipe to another without consuming it
+#[cfg(target_os = "linux")]
+pub fn tee<In: AsFd, Out: AsFd>(in_pipe: In, out_pipe: Out, length: usize, flags: SpliceFlags) -> CResult<usize> {
+	transferred(unsafe { libc::tee(in_pipe.as_fd().as_raw_fd(), out_pipe.as_fd().as_raw_fd(), length, flags.bits()) })
+}
+
+// Hands memory to a pipe; the pipe may reference the pages rather than copy
+// them, so changes made to bufs before the data is read can show up in it
+#[cfg(target_os = "linux")]
+pub fn vmsplice<Pipe: AsFd>(pipe: Pipe, bufs: &[IoSlice<'_>], flags: SpliceFlags) -> CResult<usize> {
+	// IoSlice is guaranteed to be ABI compatible with iovec
+	transferred(unsafe { libc::vmsplice(pipe.as_fd().as_raw_fd(), bufs.as_ptr() as *const libc::iovec, bufs.len(), flags.bits()) })
+}
+
+// Copies between regular files, letting the filesystem share extents or copy
+// on the server where it can
+#[cfg(target_os = "linux")]
+pub fn copy_file_range<In: AsFd, Out: AsFd>(in_fd: In, in_offset: Option<&mut libc::off_t>, out_fd: Out, out_offset: Option<&mut libc::off_t>, length: usize) -> CResult<usize> {
+	transferred(unsafe {
+		libc::copy_file_range(
+			in_fd.as_fd().as_raw_fd(),
+			offset_ptr(in_offset),
+			out_fd.as_fd().as_raw_fd(),
+			offset_ptr(out_offset),
+			length.min(MAX_CHUNK),
+			0,
+		)
+	})
+}
+
+// Errors that mean the mechanism can't be used on these descriptors, rather
+// than that the copy failed
+#[cfg(target_os = "linux")]
+fn unsupported(err: CError) -> bool {
+	matches!(libc::c_int::from(err), libc::EINVAL | libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EBADF)
+}
+
+// Runs step until it reports the end of the input, or returns None if the
+// first step shows the mechanism doesn't apply
+#[cfg(target_os = "linux")]
+fn copy_with<Step: FnMut() -> CResult<usize>>(mut step: Step) -> CResult<Option<u64>> {
+	let mut total: u64 = 0;
+	loop {
+		match step() {
+			Ok(0) => return Ok(Some(total)),
+			Ok(bytes) => total += bytes as u64,
+			Err(CError::Interrupted) => {},
+			Err(err) if total == 0 && unsupported(err) => return Ok(None),
+			Err(err) => return Err(err),
+		}
+	}
+}
+
+fn copy_with_buffer(src: libc::c_int, dst: libc::c_int) -> CResult<u64> {
+	let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+	let mut total: u64 = 0;
+	loop {
+		let bytes_read = match unsafe { libc::read(src, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) } {
+			-1 => match CError::new_from_errno() {
+				CError::Interrupted => continue,
+				err => return Err(err),
+			},
+			0 => return Ok(total),
+			bytes_read => bytes_read as usize,
+		};
+		let mut written = 0;
+		while written < bytes_read {
+			match unsafe { libc::write(dst, buffer[written..].as_ptr() as *const libc::c_void, bytes_read - written) } {
+				-1 => match CError::new_from_errno() {
+					CError::Interrupted => {},
+					err => return Err(err),
+				},
+				0 => return Err(CError::IO),
+				bytes_written => written += bytes_written as usize,
+			}
+		}
+		total += bytes_read as u64;
+	}
+}
+
+// Copies everything from src's position to its end into dst, and returns
+// how many bytes were copied. Tries copy_file_range, then splice, then
+// sendfile, and falls back to reading and writing through a buffer
+pub fn copy<Src: AsFd, Dst: AsFd>(src: Src, dst: Dst) -> CResult<u64> {
+	let src = src.as_fd().as_raw_fd();
+	let dst = dst.as_fd().as_raw_fd();
+
+	#[cfg(target_os = "linux")]
+	{
+		let src = unsafe { super::BorrowedFileDescriptor::borrow_raw(src) };
+		let dst = unsafe { super::BorrowedFileDescriptor::borrow_raw(dst) };
+
+		// Files in /proc and similar report a size of 0 and read as empty
+		// through copy_file_range, so it is only trusted with a known size
+		let src_metadata = super::FileDescriptor::wrap_unowned(src.fd, |fd| fd.fstat())?;
+		if src_metadata.file_type == super::stat::FileType::Regular && src_metadata.size > 0 {
+			if let Some(total) = copy_with(|| copy_file_range(src, None, dst, None, MAX_CHUNK))? {
+				return Ok(total);
+			}
+		}
+		if let Some(total) = copy_with(|| splice(src, None, dst, None, MAX_CHUNK, SpliceFlags::MOVE))? {
+			return Ok(total);
+		}
+		if let Some(total) = copy_with(|| sendfile(dst, src, None, MAX_CHUNK))? {
+			return Ok(total);
+		}
+		copy_with_buffer(src.fd, dst.fd)
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	copy_with_buffer(src, dst)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use std::ffi::CString;
+
+	use super::*;
+	use crate::{file::{memfd::{memfd_create, MemfdFlags}, FileDescriptor}, pipe::{pipe, PipeResult}};
+
+	fn file_with(data: &[u8]) -> FileDescriptor {
+		let mut fd = memfd_create(CString::new("transfer-test").unwrap(), MemfdFlags::CLOEXEC).unwrap();
+		assert_eq!(fd.write_slice(data).unwrap(), data.len());
+		fd
+	}
+
+	fn contents(fd: &FileDescriptor) -> Vec<u8> {
+		let mut buffer = vec![0u8; fd.fstat().unwrap().size as usize];
+		let bytes_read = unsafe { libc::pread(fd.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0) };
+		assert_eq!(bytes_read as usize, buffer.len());
+		buffer
+	}
+
+	#[test]
+	fn copy_with_adds_up_partial_steps_until_the_end() {
+		let mut steps = vec![Ok(0), Ok(1), Err(CError::Interrupted), Ok(2), Ok(3)];
+		assert_eq!(copy_with(|| steps.pop().unwrap()).unwrap(), Some(6));
+	}
+
+	#[test]
+	fn copy_with_gives_up_only_before_any_progress() {
+		assert_eq!(copy_with(|| Err(CError::Invalid)).unwrap(), None);
+		let mut steps = vec![Err(CError::Invalid), Ok(3)];
+		assert!(matches!(copy_with(|| steps.pop().unwrap()), Err(CError::Invalid)));
+	}
+
+	#[test]
+	fn sendfile_moves_part_of_a_file_and_then_reaches_the_end() {
+		let src = file_with(b"0123456789");
+		let mut pipe = pipe().unwrap();
+		let mut offset = 4;
+		assert_eq!(sendfile(&pipe.write_fd, &src, Some(&mut offset), 3).unwrap(), 3);
+		assert_eq!(offset, 7);
+		assert_eq!(pipe.read_fd.read_bytes(16).unwrap(), b"456");
+		let mut offset = 10;
+		assert_eq!(sendfile(&pipe.write_fd, &src, Some(&mut offset), 3).unwrap(), 0);
+	}
+
+	#[test]
+	fn splice_moves_part_of_a_pipe_and_reports_its_end() {
+		let PipeResult { read_fd, mut write_fd } = pipe().unwrap();
+		write_fd.write_slice(b"hello").unwrap();
+		let dst = file_with(b"");
+		let mut offset = 0;
+		assert_eq!(splice(&read_fd, None, &dst, Some(&mut offset), 3, SpliceFlags::empty()).unwrap(), 3);
+		assert_eq!(contents(&dst), b"hel");
+		drop(write_fd);
+		assert_eq!(splice(&read_fd, None, &dst, Some(&mut offset), 16, SpliceFlags::empty()).unwrap(), 2);
+		assert_eq!(splice(&read_fd, None, &dst, Some(&mut offset), 16, SpliceFlags::empty()).unwrap(), 0);
+		assert_eq!(contents(&dst), b"hello");
+	}
+
+	#[test]
+	fn tee_copies_without_consuming() {
+		let mut first = pipe().unwrap();
+		let mut second = pipe().unwrap();
+		first.write_fd.write_slice(b"hello").unwrap();
+		assert_eq!(tee(&first.read_fd, &second.write_fd, 3, SpliceFlags::empty()).unwrap(), 3);
+		assert_eq!(second.read_fd.read_bytes(16).unwrap(), b"hel");
+		assert_eq!(first.read_fd.read_bytes(16).unwrap(), b"hello");
+	}
+
+	#[test]
+	fn copy_file_range_copies_part_of_a_file_and_then_reaches_the_end() {
+		let src = file_with(b"0123456789");
+		let dst = file_with(b"");
+		let (mut in_offset, mut out_offset) = (2, 0);
+		assert_eq!(copy_file_range(&src, Some(&mut in_offset), &dst, Some(&mut out_offset), 4).unwrap(), 4);
+		assert_eq!((in_offset, out_offset), (6, 4));
+		assert_eq!(contents(&dst), b"2345");
+		let mut in_offset = 10;
+		assert_eq!(copy_file_range(&src, Some(&mut in_offset), &dst, None, 4).unwrap(), 0);
+	}
+
+	#[test]
+	fn copy_moves_everything_in_several_steps() {
+		let data: Vec<u8> = (0..3 * COPY_BUFFER_SIZE).map(|i| i as u8).collect();
+		let src = file_with(&data);
+		unsafe { libc::lseek(src.fd, 0, libc::SEEK_SET) };
+		let dst = file_with(b"");
+		assert_eq!(copy(&src, &dst).unwrap(), data.len() as u64);
+		assert_eq!(contents(&dst), data);
+	}
+}
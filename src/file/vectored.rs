@@ -0,0 +1,134 @@
+use std::io::{IoSlice, IoSliceMut};
+
+use crate::{c_error::CError, c_result::CResult};
+
+use super::FileDescriptor;
+
+// The most buffers a single readv/writev call accepts
+#[cfg(target_os = "linux")]
+static IOV_MAX: usize = 1024;
+#[cfg(not(target_os = "linux"))]
+static IOV_MAX: usize = 16;
+
+// IoSlice and IoSliceMut are guaranteed to be ABI compatible with iovec
+pub(crate) fn readv(fd: libc::c_int, bufs: &mut [IoSliceMut<'_>]) -> CResult<usize> {
+	let count = bufs.len().min(IOV_MAX) as libc::c_int;
+	match unsafe { libc::readv(fd, bufs.as_mut_ptr() as *const libc::iovec, count) } {
+		-1 => Err(CError::new_from_errno()),
+		bytes_read => Ok(bytes_read as usize),
+	}
+}
+
+pub(crate) fn writev(fd: libc::c_int, bufs: &[IoSlice<'_>]) -> CResult<usize> {
+	let count = bufs.len().min(IOV_MAX) as libc::c_int;
+	match unsafe { libc::writev(fd, bufs.as_ptr() as *const libc::iovec, count) } {
+		-1 => Err(CError::new_from_errno()),
+		bytes_written => Ok(bytes_written as usize),
+	}
+}
+
+impl FileDescriptor {
+	pub fn readv(&self, bufs: &mut [IoSliceMut<'_>]) -> CResult<usize> {
+		readv(self.fd, bufs)
+	}
+
+	pub fn writev(&self, bufs: &[IoSlice<'_>]) -> CResult<usize> {
+		writev(self.fd, bufs)
+	}
+
+	pub fn write_all_vectored(&self, bufs: &[&[u8]]) -> CResult<()> {
+		write_all_with(bufs, |remaining| writev(self.fd, remaining))
+	}
+}
+
+// Calls write, which may write only part of what it is given, until every
+// buffer has been written
+fn write_all_with<Write: FnMut(&[IoSlice<'_>]) -> CResult<usize>>(bufs: &[&[u8]], mut write: Write) -> CResult<()> {
+	// Index of the first buffer not fully written, and how much of it was
+	let mut index = 0;
+	let mut offset = 0;
+
+	while index < bufs.len() {
+		let remaining: Vec<IoSlice> = std::iter::once(&bufs[index][offset..])
+			.chain(bufs[index + 1..].iter().copied())
+			.map(IoSlice::new)
+			.collect();
+		let mut written = match write(&remaining) {
+			Ok(0) if remaining.iter().any(|buf| !buf.is_empty()) => return Err(CError::IO),
+			Ok(written) => written,
+			Err(CError::Interrupted) => continue,
+			Err(err) => return Err(err),
+		};
+
+		// Skip past every buffer the write finished, including empty ones
+		while index < bufs.len() && written >= bufs[index].len() - offset {
+			written -= bufs[index].len() - offset;
+			index += 1;
+			offset = 0;
+		}
+		offset += written;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pipe::pipe;
+
+	// Accepts at most limit bytes per call, like a writev cut short
+	fn write_in_pieces(bufs: &[&[u8]], limit: usize) -> (CResult<()>, Vec<u8>, usize) {
+		let mut output = vec![];
+		let mut calls = 0;
+		let result = write_all_with(bufs, |remaining| {
+			calls += 1;
+			let mut accepted = 0;
+			for buf in remaining {
+				let take = buf.len().min(limit - accepted);
+				output.extend_from_slice(&buf[..take]);
+				accepted += take;
+			}
+			Ok(accepted)
+		});
+		(result, output, calls)
+	}
+
+	#[test]
+	fn partial_writes_resume_where_they_stopped() {
+		let bufs: [&[u8]; 5] = [b"hello", b"", b" ", b"vectored", b" world"];
+		for limit in 1..=20 {
+			let (result, output, _) = write_in_pieces(&bufs, limit);
+			result.unwrap();
+			assert_eq!(output, b"hello vectored world", "limit {}", limit);
+		}
+	}
+
+	#[test]
+	fn only_empty_buffers_need_one_call() {
+		let (result, output, calls) = write_in_pieces(&[b"", b""], 4);
+		result.unwrap();
+		assert!(output.is_empty());
+		assert_eq!(calls, 1);
+	}
+
+	#[test]
+	fn interrupted_writes_are_retried_and_other_errors_returned() {
+		let mut interrupted = false;
+		let result = write_all_with(&[b"abc"], |remaining| {
+			if !interrupted {
+				interrupted = true;
+				return Err(CError::Interrupted);
+			}
+			Ok(remaining[0].len())
+		});
+		assert!(result.is_ok());
+		assert!(matches!(write_all_with(&[b"abc"], |_| Ok(0)), Err(CError::IO)));
+	}
+
+	#[test]
+	fn writes_every_buffer_to_a_pipe() {
+		let mut pipe = pipe().unwrap();
+		pipe.write_fd.write_all_vectored(&[b"one ", b"two ", b"three"]).unwrap();
+		assert_eq!(pipe.read_fd.read_bytes(64).unwrap(), b"one two three");
+	}
+}
@@ -12,3 +12,36 @@ pub enum ForkResult {
 	Child,
 	Parent(libc::pid_t),
 }
+
+// For the child side of helpers that set up a forked process before handing
+// it back: returning an error there would run the caller's error handling in
+// the child as if it were the parent, so the child exits with 127 instead,
+// like a shell that can't run a command
+pub(crate) fn exit_child_on_error<T>(result: CResult<T>) -> T {
+	match result {
+		Ok(value) => value,
+		Err(_) => unsafe { libc::_exit(127) },
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::wait::waitpid;
+
+	#[test]
+	fn failing_child_setup_exits_instead_of_returning() {
+		match fork().unwrap() {
+			ForkResult::Child => {
+				exit_child_on_error::<()>(Err(CError::Invalid));
+				// Only reached if the error was returned
+				unsafe { libc::_exit(0) }
+			},
+			ForkResult::Parent(pid) => {
+				let result = waitpid(pid).unwrap();
+				assert!(result.status.exited_normally());
+				assert_eq!(result.status.exit_status(), 127);
+			},
+		}
+	}
+}
@@ -0,0 +1,546 @@
+use std::{collections::HashMap, ffi::CString, iter::Peekable, os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd}};
+
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult, file::{dir::read_dir, stat::FileType, FileDescriptor}};
+
+// Enough for dozens of events, and always more than the one event with the
+// longest name the kernel requires a read to fit
+static EVENT_BUFFER_SIZE: usize = 16 * 1024;
+static EVENT_HEADER_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+
+bitflags! {
+	pub struct InotifyFlags: libc::c_int {
+		const CLOEXEC = libc::IN_CLOEXEC;
+		const NONBLOCK = libc::IN_NONBLOCK;
+	}
+}
+
+bitflags! {
+	// What to watch for, and how, when adding a watch
+	pub struct WatchMask: u32 {
+		const ACCESS = libc::IN_ACCESS;
+		const MODIFY = libc::IN_MODIFY;
+		const ATTRIB = libc::IN_ATTRIB;
+		const CLOSE_WRITE = libc::IN_CLOSE_WRITE;
+		const CLOSE_NOWRITE = libc::IN_CLOSE_NOWRITE;
+		const CLOSE = libc::IN_CLOSE;
+		const OPEN = libc::IN_OPEN;
+		const MOVED_FROM = libc::IN_MOVED_FROM;
+		const MOVED_TO = libc::IN_MOVED_TO;
+		const MOVE = libc::IN_MOVE;
+		const CREATE = libc::IN_CREATE;
+		const DELETE = libc::IN_DELETE;
+		const DELETE_SELF = libc::IN_DELETE_SELF;
+		const MOVE_SELF = libc::IN_MOVE_SELF;
+		const ALL_EVENTS = libc::IN_ALL_EVENTS;
+		// Fail with NotADirectory unless the path is a directory
+		const ONLYDIR = libc::IN_ONLYDIR;
+		// Watch a final symlink itself
+		const DONT_FOLLOW = libc::IN_DONT_FOLLOW;
+		// Stop reporting events for children once they are unlinked
+		const EXCL_UNLINK = libc::IN_EXCL_UNLINK;
+		// Add to the mask of an existing watch instead of replacing it
+		const MASK_ADD = libc::IN_MASK_ADD;
+		// Remove the watch after its first event
+		const ONESHOT = libc::IN_ONESHOT;
+		// Fail with AlreadyExists if the path is already watched
+		const MASK_CREATE = libc::IN_MASK_CREATE;
+	}
+}
+
+bitflags! {
+	// What happened, as reported in an event
+	pub struct EventMask: u32 {
+		const ACCESS = libc::IN_ACCESS;
+		const MODIFY = libc::IN_MODIFY;
+		const ATTRIB = libc::IN_ATTRIB;
+		const CLOSE_WRITE = libc::IN_CLOSE_WRITE;
+		const CLOSE_NOWRITE = libc::IN_CLOSE_NOWRITE;
+		const OPEN = libc::IN_OPEN;
+		const MOVED_FROM = libc::IN_MOVED_FROM;
+		const MOVED_TO = libc::IN_MOVED_TO;
+		const CREATE = libc::IN_CREATE;
+		const DELETE = libc::IN_DELETE;
+		const DELETE_SELF = libc::IN_DELETE_SELF;
+		const MOVE_SELF = libc::IN_MOVE_SELF;
+		// The filesystem holding the watched path was unmounted
+		const UNMOUNT = libc::IN_UNMOUNT;
+		// Events were lost because the queue filled up
+		const Q_OVERFLOW = libc::IN_Q_OVERFLOW;
+		// The watch was removed, explicitly or because its path is gone
+		const IGNORED = libc::IN_IGNORED;
+		// The event is about a directory
+		const ISDIR = libc::IN_ISDIR;
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatchDescriptor(libc::c_int);
+
+impl WatchDescriptor {
+	pub fn raw(self) -> libc::c_int {
+		self.0
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InotifyEvent {
+	// -1 for Q_OVERFLOW
+	pub watch: WatchDescriptor,
+	pub mask: EventMask,
+	// Shared by the MOVED_FROM and MOVED_TO events of a single rename
+	pub cookie: u32,
+	// The child of a watched directory the event is about; None when it is
+	// about the watched path itself
+	pub name: Option<CString>,
+}
+
+// Parses the variable-length records filled in by a read
+pub struct Events<'a> {
+	buffer: &'a [u8],
+}
+
+impl Iterator for Events<'_> {
+	type Item = InotifyEvent;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.buffer.len() < EVENT_HEADER_SIZE {
+			return None;
+		}
+		// The records are only aligned by accident
+		let header = unsafe { std::ptr::read_unaligned(self.buffer.as_ptr() as *const libc::inotify_event) };
+		let end = EVENT_HEADER_SIZE + header.len as usize;
+		// The name is padded with NULs up to len
+		let name = &self.buffer[EVENT_HEADER_SIZE..end];
+		let name = match name.iter().position(|&byte| byte == 0).unwrap_or(name.len()) {
+			0 => None,
+			length => Some(CString::new(&name[..length]).unwrap()),
+		};
+		self.buffer = &self.buffer[end..];
+		Some(InotifyEvent {
+			watch: WatchDescriptor(header.wd),
+			mask: EventMask::from_bits_truncate(header.mask),
+			cookie: header.cookie,
+			name,
+		})
+	}
+}
+
+pub struct Inotify {
+	fd: FileDescriptor,
+	buffer: Vec<u8>,
+}
+
+impl Inotify {
+	pub fn new(flags: InotifyFlags) -> CResult<Self> {
+		match unsafe { libc::inotify_init1(flags.bits()) } {
+			-1 => Err(CError::new_from_errno()),
+			fd => Ok(Self {
+				fd: unsafe { FileDescriptor::from_unowned(fd) },
+				buffer: vec![0; EVENT_BUFFER_SIZE],
+			}),
+		}
+	}
+
+	pub fn fd(&self) -> &FileDescriptor {
+		&self.fd
+	}
+
+	pub fn into_fd(self) -> FileDescriptor {
+		self.fd
+	}
+
+	pub fn set_nonblocking(&mut self, nonblocking: bool) -> CResult<()> {
+		self.fd.set_nonblocking(nonblocking)
+	}
+
+	// Watching a path that is already watched returns the same descriptor
+	// and replaces its mask, unless MASK_ADD or MASK_CREATE is given
+	pub fn add_watch<Path: Into<CString>>(&self, pathname: Path, mask: WatchMask) -> CResult<WatchDescriptor> {
+		let pathname: CString = pathname.into();
+		match unsafe { libc::inotify_add_watch(self.fd.fd, pathname.as_ptr(), mask.bits()) } {
+			-1 => Err(CError::new_from_errno()),
+			wd => Ok(WatchDescriptor(wd)),
+		}
+	}
+
+	// An IGNORED event for the watch follows
+	pub fn rm_watch(&self, watch: WatchDescriptor) -> CResult<()> {
+		match unsafe { libc::inotify_rm_watch(self.fd.fd, watch.0) } {
+			0 => Ok(()),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("inotify_rm_watch returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+
+	// Blocks until there is at least one event, or fails with Again when
+	// non-blocking
+	pub fn read_events(&mut self) -> CResult<Events<'_>> {
+		loop {
+			match unsafe { libc::read(self.fd.fd, self.buffer.as_mut_ptr() as *mut libc::c_void, self.buffer.len()) } {
+				-1 => match CError::new_from_errno() {
+					CError::Interrupted => continue,
+					err => return Err(err),
+				},
+				bytes_read => return Ok(Events {
+					buffer: &self.buffer[..bytes_read as usize],
+				}),
+			}
+		}
+	}
+
+	// Returns None instead of failing with Again, for non-blocking use
+	pub fn try_read_events(&mut self) -> CResult<Option<Events<'_>>> {
+		match self.read_events() {
+			Ok(events) => Ok(Some(events)),
+			Err(CError::Again) | Err(CError::WouldBlock) => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+}
+
+impl AsRawFd for Inotify {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.fd
+	}
+}
+
+impl AsFd for Inotify {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.fd.as_fd()
+	}
+}
+
+impl From<Inotify> for FileDescriptor {
+	fn from(inotify: Inotify) -> Self {
+		inotify.fd
+	}
+}
+
+// Events that can take part in a rename, for pair_moves
+pub trait MoveEvent {
+	fn mask(&self) -> EventMask;
+	fn cookie(&self) -> u32;
+}
+
+impl MoveEvent for InotifyEvent {
+	fn mask(&self) -> EventMask {
+		self.mask
+	}
+
+	fn cookie(&self) -> u32 {
+		self.cookie
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<Event> {
+	Event(Event),
+	// Both ends of a rename; a rename into or out of the watched paths only
+	// has one end, which comes as a plain Event
+	Moved {
+		from: Event,
+		to: Event,
+	},
+}
+
+pub struct PairedMoves<I: Iterator> {
+	events: Peekable<I>,
+}
+
+impl<I: Iterator> Iterator for PairedMoves<I>
+where
+	I::Item: MoveEvent,
+{
+	type Item = Change<I::Item>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let event = self.events.next()?;
+		if !event.mask().contains(EventMask::MOVED_FROM) {
+			return Some(Change::Event(event));
+		}
+		let cookie = event.cookie();
+		match self.events.next_if(|next| next.mask().contains(EventMask::MOVED_TO) && next.cookie() == cookie) {
+			Some(to) => Some(Change::Moved {
+				from: event,
+				to,
+			}),
+			None => Some(Change::Event(event)),
+		}
+	}
+}
+
+// Joins the MOVED_FROM and MOVED_TO events of each rename. The kernel queues
+// them next to each other, but a read can still end between the two, in
+// which case they come out as separate Events
+pub fn pair_moves<I: IntoIterator>(events: I) -> PairedMoves<I::IntoIter>
+where
+	I::Item: MoveEvent,
+{
+	PairedMoves {
+		events: events.into_iter().peekable(),
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+	// The watched root joined with the path below it
+	pub path: CString,
+	pub mask: EventMask,
+	pub cookie: u32,
+}
+
+impl MoveEvent for WatchEvent {
+	fn mask(&self) -> EventMask {
+		self.mask
+	}
+
+	fn cookie(&self) -> u32 {
+		self.cookie
+	}
+}
+
+// A directory below the root that couldn't be watched, such as one that isn't
+// readable or that hit the limit on watches; changes in it are missed
+#[derive(Debug, Clone)]
+pub struct WatchError {
+	pub path: CString,
+	pub error: CError,
+}
+
+// What a read turned up: errors don't stop the rest of the events from being
+// reported, or the other new directories from being watched
+#[derive(Debug, Clone, Default)]
+pub struct WatchEvents {
+	pub events: Vec<WatchEvent>,
+	pub errors: Vec<WatchError>,
+}
+
+fn join(parent: &CString, name: &CString) -> CString {
+	let mut path = parent.as_bytes().to_vec();
+	if !path.ends_with(b"/") {
+		path.push(b'/');
+	}
+	path.extend_from_slice(name.as_bytes());
+	CString::new(path).unwrap()
+}
+
+// Watches a directory and everything below it, adding watches for
+// directories as they are created or moved in
+pub struct RecursiveWatcher {
+	inotify: Inotify,
+	mask: WatchMask,
+	root: CString,
+	paths: HashMap<WatchDescriptor, CString>,
+}
+
+impl RecursiveWatcher {
+	// mask only needs the events wanted; the ones needed to follow new
+	// directories are added, but not reported unless asked for
+	pub fn new<Path: Into<CString>>(root: Path, mask: WatchMask, flags: InotifyFlags) -> CResult<Self> {
+		let mut watcher = Self {
+			inotify: Inotify::new(flags)?,
+			mask,
+			root: root.into(),
+			paths: HashMap::new(),
+		};
+		let mut errors = Vec::new();
+		watcher.watch_tree(watcher.root.clone(), None, &mut errors)?;
+		// The whole tree is asked for, so part of it isn't good enough
+		match errors.into_iter().next() {
+			Some(WatchError { error, .. }) => Err(error),
+			None => Ok(watcher),
+		}
+	}
+
+	pub fn inotify(&self) -> &Inotify {
+		&self.inotify
+	}
+
+	pub fn set_nonblocking(&mut self, nonblocking: bool) -> CResult<()> {
+		self.inotify.set_nonblocking(nonblocking)
+	}
+
+	pub fn watched_paths(&self) -> impl Iterator<Item = &CString> {
+		self.paths.values()
+	}
+
+	// For a directory that just appeared, anything created in it before the
+	// watch was added would go unnoticed, so it is reported through found.
+	// Fails if path itself can't be watched or listed; entries below it that
+	// can't be are added to errors instead, and the walk goes on
+	fn watch_tree(&mut self, path: CString, mut found: Option<&mut Vec<WatchEvent>>, errors: &mut Vec<WatchError>) -> CResult<()> {
+		let mask = self.mask | WatchMask::CREATE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO | WatchMask::ONLYDIR | WatchMask::DONT_FOLLOW;
+		let watch = self.inotify.add_watch(path.clone(), mask)?;
+		self.paths.insert(watch, path.clone());
+		for entry in read_dir(path.clone())? {
+			// The rest of the listing can't be trusted after a failed read
+			let entry = match entry {
+				Ok(entry) => entry,
+				Err(error) => {
+					errors.push(WatchError {
+						path,
+						error,
+					});
+					break;
+				},
+			};
+			// Without d_type this stats the entry, which may be gone already
+			let file_type = entry.file_type();
+			let entry_path = join(&path, &entry.into_name());
+			let is_dir = match file_type {
+				Ok(file_type) => file_type == FileType::Directory,
+				Err(CError::NotFound) => continue,
+				Err(error) => {
+					errors.push(WatchError {
+						path: entry_path,
+						error,
+					});
+					continue;
+				},
+			};
+			if let Some(found) = found.as_deref_mut() {
+				if self.mask.contains(WatchMask::CREATE) {
+					found.push(WatchEvent {
+						path: entry_path.clone(),
+						mask: if is_dir { EventMask::CREATE | EventMask::ISDIR } else { EventMask::CREATE },
+						cookie: 0,
+					});
+				}
+			}
+			if !is_dir {
+				continue;
+			}
+			match self.watch_tree(entry_path.clone(), found.as_deref_mut(), errors) {
+				// Removed or replaced since it was listed
+				Ok(()) | Err(CError::NotFound) | Err(CError::NotADirectory) => {},
+				Err(error) => errors.push(WatchError {
+					path: entry_path,
+					error,
+				}),
+			}
+		}
+		Ok(())
+	}
+
+	// Stops watching path and everything below it, for a directory moved
+	// away; if it was moved within the tree, it is watched again once its
+	// MOVED_TO is seen
+	fn unwatch_tree(&mut self, path: &CString) {
+		let mut prefix = path.as_bytes().to_vec();
+		prefix.push(b'/');
+		let watches: Vec<WatchDescriptor> = self.paths.iter()
+			.filter(|(_, watched)| watched == &path || watched.as_bytes().starts_with(&prefix))
+			.map(|(watch, _)| *watch)
+			.collect();
+		for watch in watches {
+			self.paths.remove(&watch);
+			let _ = self.inotify.rm_watch(watch);
+		}
+	}
+
+	// Blocks until there is at least one event, or fails with Again when
+	// non-blocking. Events from watches removed in the meantime are dropped,
+	// so the result can be empty
+	pub fn read_events(&mut self) -> CResult<WatchEvents> {
+		let events: Vec<InotifyEvent> = self.inotify.read_events()?.collect();
+		let mut watch_events = Vec::new();
+		let mut errors = Vec::new();
+		for event in events {
+			if event.mask.contains(EventMask::Q_OVERFLOW) {
+				watch_events.push(WatchEvent {
+					path: self.root.clone(),
+					mask: event.mask,
+					cookie: 0,
+				});
+				continue;
+			}
+			let path = match (self.paths.get(&event.watch), &event.name) {
+				(Some(parent), Some(name)) => join(parent, name),
+				(Some(path), None) => path.clone(),
+				(None, _) => continue,
+			};
+			if event.mask.contains(EventMask::IGNORED) {
+				self.paths.remove(&event.watch);
+				continue;
+			}
+			if event.mask.contains(EventMask::ISDIR | EventMask::MOVED_FROM) {
+				self.unwatch_tree(&path);
+			}
+			if event.mask.bits() & self.mask.bits() & WatchMask::ALL_EVENTS.bits() != 0 {
+				watch_events.push(WatchEvent {
+					path: path.clone(),
+					mask: event.mask,
+					cookie: event.cookie,
+				});
+			}
+			// After the event itself, so the contents are reported after it
+			if event.mask.contains(EventMask::ISDIR) && event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+				// A directory moved in has no new contents, only an existing tree
+				let found = if event.mask.contains(EventMask::CREATE) { Some(&mut watch_events) } else { None };
+				match self.watch_tree(path.clone(), found, &mut errors) {
+					Ok(()) | Err(CError::NotFound) | Err(CError::NotADirectory) => {},
+					Err(error) => errors.push(WatchError {
+						path,
+						error,
+					}),
+				}
+			}
+		}
+		Ok(WatchEvents {
+			events: watch_events,
+			errors,
+		})
+	}
+}
+
+impl AsRawFd for RecursiveWatcher {
+	fn as_raw_fd(&self) -> RawFd {
+		self.inotify.as_raw_fd()
+	}
+}
+
+impl AsFd for RecursiveWatcher {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.inotify.as_fd()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::file::dir::Dir;
+
+	fn c_path(path: &std::path::Path) -> CString {
+		CString::new(path.to_str().unwrap()).unwrap()
+	}
+
+	#[test]
+	fn a_directory_that_cant_be_watched_doesnt_lose_the_batch() {
+		let base = std::env::temp_dir().join(format!("c_wrapper-inotify-{}", std::process::id()));
+		std::fs::create_dir_all(base.join("root")).unwrap();
+		// Deep enough that the path names below it are too long to watch
+		let name = CString::new(vec![b'd'; 255]).unwrap();
+		let mut dir = Dir::from_path(c_path(&base)).unwrap();
+		dir.mkdir(CString::new("deep").unwrap(), 0o755).unwrap();
+		dir = dir.open_dir(CString::new("deep").unwrap()).unwrap();
+		for _ in 0..20 {
+			dir.mkdir(name.clone(), 0o755).unwrap();
+			dir = dir.open_dir(name.clone()).unwrap();
+		}
+
+		let root = base.join("root");
+		let mut watcher = RecursiveWatcher::new(c_path(&root), WatchMask::CREATE | WatchMask::MOVED_TO, InotifyFlags::CLOEXEC).unwrap();
+		std::fs::rename(base.join("deep"), root.join("deep")).unwrap();
+		std::fs::write(root.join("after"), b"").unwrap();
+		let result = watcher.read_events();
+		std::fs::remove_dir_all(&base).unwrap();
+
+		let result = result.unwrap();
+		let paths: Vec<CString> = result.events.into_iter().map(|event| event.path).collect();
+		assert_eq!(paths, vec![c_path(&root.join("deep")), c_path(&root.join("after"))]);
+		assert_eq!(result.errors.len(), 1);
+		assert!(matches!(result.errors[0].error, CError::Unknown(libc::ENAMETOOLONG)));
+	}
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::missing_safety_doc)]
+
 pub mod fork;
 pub mod c_error;
 pub mod c_result;
@@ -7,6 +9,29 @@ pub mod file;
 pub mod pipe;
 pub mod cwd;
 pub mod chdir;
+pub mod communicate;
+#[cfg(target_os = "linux")]
+pub mod reaper;
+#[cfg(target_os = "linux")]
+pub mod namespace;
+#[cfg(target_os = "linux")]
+pub mod prctl;
+pub mod daemon;
+pub mod pty;
+pub mod line_editor;
+pub mod expect;
+pub mod mmap;
+pub mod poll;
+#[cfg(target_os = "linux")]
+pub mod eventfd;
+#[cfg(target_os = "linux")]
+pub mod timerfd;
+#[cfg(target_os = "linux")]
+pub mod signalfd;
+#[cfg(target_os = "linux")]
+pub mod inotify;
+#[cfg(all(feature = "async", target_os = "linux"))]
+pub mod async_io;
 pub mod types {
 	pub use libc::{
 		c_int,
@@ -0,0 +1,547 @@
+use std::{collections::VecDeque, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::{c_error::CError, c_result::CResult, file::FileDescriptor};
+
+static DEFAULT_HISTORY_LIMIT: usize = 1000;
+static DEFAULT_COLUMNS: usize = 80;
+static READ_BUFFER_SIZE: usize = 64;
+// How long to wait after an Escape for the rest of a sequence; terminals send
+// a whole sequence at once, so a lone Escape is a keypress
+static ESCAPE_TIMEOUT_MILLIS: libc::c_int = 50;
+
+static WINDOW_RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigwinch(_signal: libc::c_int) {
+	WINDOW_RESIZED.store(true, Ordering::SeqCst);
+}
+
+// Installs the SIGWINCH handler for as long as it's alive
+struct ResizeHandlerGuard {
+	previous: libc::sigaction,
+}
+
+impl ResizeHandlerGuard {
+	fn install() -> CResult<Self> {
+		let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+		action.sa_sigaction = handle_sigwinch as *const () as libc::sighandler_t;
+		// No SA_RESTART, so a blocked read returns with EINTR and we can redraw
+		action.sa_flags = 0;
+		let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+		match unsafe { libc::sigaction(libc::SIGWINCH, &action, &mut previous) } {
+			0 => Ok(Self { previous }),
+			_ => Err(CError::new_from_errno()),
+		}
+	}
+}
+
+impl Drop for ResizeHandlerGuard {
+	fn drop(&mut self) {
+		unsafe { libc::sigaction(libc::SIGWINCH, &self.previous, std::ptr::null_mut()) };
+	}
+}
+
+pub enum ReadLine {
+	Line(String),
+	// Ctrl-D on an empty line, or the end of non-terminal input
+	Eof,
+	// Ctrl-C
+	Interrupted,
+}
+
+pub struct Completion {
+	// Index, in chars, where the text being completed starts
+	pub start: usize,
+	pub candidates: Vec<String>,
+}
+
+// Receives the line and the cursor position, in chars
+pub type Completer<'a> = Box<dyn FnMut(&str, usize) -> Completion + 'a>;
+
+pub struct LineEditor<'a> {
+	input: FileDescriptor,
+	output: FileDescriptor,
+	history: Vec<String>,
+	history_limit: usize,
+	completer: Option<Completer<'a>>,
+	pending_input: VecDeque<u8>,
+}
+
+enum Key {
+	Char(char),
+	Control(u8),
+	Backspace,
+	Delete,
+	Left,
+	Right,
+	Up,
+	Down,
+	Home,
+	End,
+	Unknown,
+}
+
+struct LineState<'p> {
+	prompt: &'p str,
+	buffer: Vec<char>,
+	cursor: usize,
+	columns: usize,
+	history_index: usize,
+	saved_line: Vec<char>,
+	last_key_was_tab: bool,
+}
+
+impl<'a> LineEditor<'a> {
+	pub fn new(input: FileDescriptor, output: FileDescriptor) -> Self {
+		Self {
+			input,
+			output,
+			history: vec![],
+			history_limit: DEFAULT_HISTORY_LIMIT,
+			completer: None,
+			pending_input: VecDeque::new(),
+		}
+	}
+
+	pub fn from_stdio() -> CResult<Self> {
+		Ok(Self::new(FileDescriptor::try_clone_stdin()?, FileDescriptor::try_clone_stdout()?))
+	}
+
+	pub fn set_completer<F: FnMut(&str, usize) -> Completion + 'a>(&mut self, completer: F) {
+		self.completer = Some(Box::new(completer));
+	}
+
+	pub fn history(&self) -> &[String] {
+		&self.history
+	}
+
+	// Empty lines and repeats of the previous entry are skipped
+	pub fn add_history<Line: Into<String>>(&mut self, line: Line) {
+		let line = line.into();
+		if line.is_empty() || self.history.last() == Some(&line) {
+			return;
+		}
+		self.history.push(line);
+		if self.history.len() > self.history_limit {
+			let excess = self.history.len() - self.history_limit;
+			self.history.drain(..excess);
+		}
+	}
+
+	pub fn set_history_limit(&mut self, limit: usize) {
+		self.history_limit = limit;
+		if self.history.len() > limit {
+			let excess = self.history.len() - limit;
+			self.history.drain(..excess);
+		}
+	}
+
+	pub fn clear_history(&mut self) {
+		self.history.clear();
+	}
+
+	pub fn read_line(&mut self, prompt: &str) -> CResult<ReadLine> {
+		if !self.input.is_a_tty() {
+			return self.read_line_cooked();
+		}
+
+		// Both guards restore the terminal when dropped, even while unwinding
+		let _raw_mode = self.input.enter_raw_mode()?;
+		let _resize_handler = ResizeHandlerGuard::install()?;
+		WINDOW_RESIZED.store(false, Ordering::SeqCst);
+
+		let mut state = LineState {
+			prompt,
+			buffer: vec![],
+			cursor: 0,
+			columns: self.columns(),
+			history_index: self.history.len(),
+			saved_line: vec![],
+			last_key_was_tab: false,
+		};
+		self.refresh(&state)?;
+
+		loop {
+			let key = match self.read_key() {
+				Ok(Some(key)) => key,
+				Ok(None) => return Ok(ReadLine::Eof),
+				Err(CError::Interrupted) => {
+					if WINDOW_RESIZED.swap(false, Ordering::SeqCst) {
+						state.columns = self.columns();
+						self.refresh(&state)?;
+					}
+					continue;
+				},
+				Err(err) => return Err(err),
+			};
+
+			let is_tab = matches!(key, Key::Control(b'\t'));
+			match key {
+				Key::Char(c) => {
+					state.buffer.insert(state.cursor, c);
+					state.cursor += 1;
+				},
+				Key::Control(b'\r') | Key::Control(b'\n') => {
+					self.write_all(b"\r\n")?;
+					return Ok(ReadLine::Line(state.buffer.iter().collect()));
+				},
+				Key::Control(0x03) => {
+					self.write_all(b"^C\r\n")?;
+					return Ok(ReadLine::Interrupted);
+				},
+				Key::Control(0x04) => {
+					if state.buffer.is_empty() {
+						self.write_all(b"\r\n")?;
+						return Ok(ReadLine::Eof);
+					}
+					if state.cursor < state.buffer.len() {
+						state.buffer.remove(state.cursor);
+					}
+				},
+				Key::Control(b'\t') => self.complete(&mut state)?,
+				Key::Control(0x01) | Key::Home => state.cursor = 0,
+				Key::Control(0x05) | Key::End => state.cursor = state.buffer.len(),
+				Key::Control(0x02) | Key::Left => state.cursor = state.cursor.saturating_sub(1),
+				Key::Control(0x06) | Key::Right => state.cursor = (state.cursor + 1).min(state.buffer.len()),
+				Key::Control(0x10) | Key::Up => self.history_previous(&mut state),
+				Key::Control(0x0e) | Key::Down => self.history_next(&mut state),
+				Key::Control(0x08) | Key::Backspace => {
+					if state.cursor > 0 {
+						state.cursor -= 1;
+						state.buffer.remove(state.cursor);
+					}
+				},
+				Key::Delete => {
+					if state.cursor < state.buffer.len() {
+						state.buffer.remove(state.cursor);
+					}
+				},
+				// Ctrl-K
+				Key::Control(0x0b) => state.buffer.truncate(state.cursor),
+				// Ctrl-U
+				Key::Control(0x15) => {
+					state.buffer.drain(..state.cursor);
+					state.cursor = 0;
+				},
+				// Ctrl-W
+				Key::Control(0x17) => {
+					let mut start = state.cursor;
+					while start > 0 && state.buffer[start - 1].is_whitespace() {
+						start -= 1;
+					}
+					while start > 0 && !state.buffer[start - 1].is_whitespace() {
+						start -= 1;
+					}
+					state.buffer.drain(start..state.cursor);
+					state.cursor = start;
+				},
+				// Ctrl-L
+				Key::Control(0x0c) => self.write_all(b"\x1b[H\x1b[2J")?,
+				Key::Control(_) | Key::Unknown => {},
+			}
+			state.last_key_was_tab = is_tab;
+			self.refresh(&state)?;
+		}
+	}
+
+	fn read_line_cooked(&mut self) -> CResult<ReadLine> {
+		let mut line = vec![];
+		loop {
+			match self.next_byte()? {
+				None if line.is_empty() => return Ok(ReadLine::Eof),
+				None | Some(b'\n') => break,
+				Some(byte) => line.push(byte),
+			}
+		}
+		Ok(ReadLine::Line(String::from_utf8_lossy(&line).into_owned()))
+	}
+
+	fn columns(&self) -> usize {
+		match self.output.window_size() {
+			Ok(size) if size.columns > 0 => size.columns as usize,
+			_ => DEFAULT_COLUMNS,
+		}
+	}
+
+	fn next_byte(&mut self) -> CResult<Option<u8>> {
+		if self.pending_input.is_empty() {
+			let bytes = self.input.read_bytes(READ_BUFFER_SIZE)?;
+			if bytes.is_empty() {
+				return Ok(None);
+			}
+			self.pending_input.extend(bytes);
+		}
+		Ok(self.pending_input.pop_front())
+	}
+
+	fn read_key(&mut self) -> CResult<Option<Key>> {
+		let byte = match self.next_byte()? {
+			Some(byte) => byte,
+			None => return Ok(None),
+		};
+		let key = match byte {
+			0x1b => self.read_escape_sequence()?,
+			0x7f => Key::Backspace,
+			0x00..=0x1f => Key::Control(byte),
+			0x20..=0x7e => Key::Char(byte as char),
+			lead => {
+				let length = match lead {
+					0xc0..=0xdf => 2,
+					0xe0..=0xef => 3,
+					0xf0..=0xf7 => 4,
+					_ => return Ok(Some(Key::Unknown)),
+				};
+				let mut bytes = vec![lead];
+				for _ in 1..length {
+					match self.next_byte()? {
+						Some(byte) => bytes.push(byte),
+						None => return Ok(None),
+					}
+				}
+				match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+					Some(c) => Key::Char(c),
+					None => Key::Unknown,
+				}
+			},
+		};
+		Ok(Some(key))
+	}
+
+	// Whether input arrives within timeout_millis, without consuming it
+	fn input_ready(&mut self, timeout_millis: libc::c_int) -> CResult<bool> {
+		if !self.pending_input.is_empty() {
+			return Ok(true);
+		}
+		let mut poll_fd = libc::pollfd { fd: self.input.fd, events: libc::POLLIN, revents: 0 };
+		loop {
+			match unsafe { libc::poll(&mut poll_fd, 1, timeout_millis) } {
+				-1 => match CError::new_from_errno() {
+					CError::Interrupted => continue,
+					err => return Err(err),
+				},
+				0 => return Ok(false),
+				_ => return Ok(true),
+			}
+		}
+	}
+
+	fn read_escape_sequence(&mut self) -> CResult<Key> {
+		// A bare Escape, or Escape followed by an unrelated key, which is left
+		// to be read on its own
+		let bare_escape = Key::Control(0x1b);
+		if !self.input_ready(ESCAPE_TIMEOUT_MILLIS)? {
+			return Ok(bare_escape);
+		}
+		let introducer = match self.next_byte()? {
+			Some(byte) if byte == b'[' || byte == b'O' => Some(byte),
+			Some(byte) => {
+				self.pending_input.push_front(byte);
+				return Ok(bare_escape);
+			},
+			None => return Ok(bare_escape),
+		};
+		let final_byte = self.next_byte()?;
+		let key = match (introducer, final_byte) {
+			(Some(b'['), Some(b'A')) | (Some(b'O'), Some(b'A')) => Key::Up,
+			(Some(b'['), Some(b'B')) | (Some(b'O'), Some(b'B')) => Key::Down,
+			(Some(b'['), Some(b'C')) | (Some(b'O'), Some(b'C')) => Key::Right,
+			(Some(b'['), Some(b'D')) | (Some(b'O'), Some(b'D')) => Key::Left,
+			(Some(b'['), Some(b'H')) | (Some(b'O'), Some(b'H')) => Key::Home,
+			(Some(b'['), Some(b'F')) | (Some(b'O'), Some(b'F')) => Key::End,
+			(Some(b'['), Some(digit)) if digit.is_ascii_digit() => {
+				// Sequences like ESC [ 3 ~, possibly with modifiers before the ~
+				let mut parameters = vec![digit];
+				loop {
+					match self.next_byte()? {
+						Some(b'~') | None => break,
+						Some(byte) => parameters.push(byte),
+					}
+				}
+				match parameters.split(|b| *b == b';').next() {
+					Some(b"1") | Some(b"7") => Key::Home,
+					Some(b"3") => Key::Delete,
+					Some(b"4") | Some(b"8") => Key::End,
+					_ => Key::Unknown,
+				}
+			},
+			_ => Key::Unknown,
+		};
+		Ok(key)
+	}
+
+	fn history_previous(&mut self, state: &mut LineState) {
+		if state.history_index == 0 {
+			return;
+		}
+		if state.history_index == self.history.len() {
+			state.saved_line = state.buffer.clone();
+		}
+		state.history_index -= 1;
+		state.buffer = self.history[state.history_index].chars().collect();
+		state.cursor = state.buffer.len();
+	}
+
+	fn history_next(&mut self, state: &mut LineState) {
+		if state.history_index >= self.history.len() {
+			return;
+		}
+		state.history_index += 1;
+		state.buffer = if state.history_index == self.history.len() {
+			state.saved_line.clone()
+		}
+		else {
+			self.history[state.history_index].chars().collect()
+		};
+		state.cursor = state.buffer.len();
+	}
+
+	fn complete(&mut self, state: &mut LineState) -> CResult<()> {
+		let completer = match self.completer.as_mut() {
+			Some(completer) => completer,
+			None => return Ok(()),
+		};
+		let line: String = state.buffer.iter().collect();
+		let completion = completer(&line, state.cursor);
+		let start = completion.start.min(state.cursor);
+
+		let replacement = match completion.candidates.len() {
+			0 => return self.write_all(b"\x07"),
+			1 => completion.candidates[0].clone(),
+			_ => common_prefix(&completion.candidates),
+		};
+		let replacement: Vec<char> = replacement.chars().collect();
+
+		if replacement.len() > state.cursor - start {
+			state.buffer.splice(start..state.cursor, replacement.iter().cloned());
+			state.cursor = start + replacement.len();
+		}
+		else if completion.candidates.len() > 1 && state.last_key_was_tab {
+			// A second Tab without progress lists the candidates
+			let listing = completion.candidates.join("  ");
+			self.write_all(format!("\r\n{}\r\n", listing).as_bytes())?;
+		}
+		else if completion.candidates.len() > 1 {
+			self.write_all(b"\x07")?;
+		}
+		Ok(())
+	}
+
+	fn refresh(&mut self, state: &LineState) -> CResult<()> {
+		let prompt_width = prompt_width(state.prompt);
+		let available = state.columns.saturating_sub(prompt_width).max(1);
+
+		// Scroll horizontally so the cursor stays visible
+		let mut start = 0;
+		while start < state.cursor && width(&state.buffer[start..state.cursor]) >= available {
+			start += 1;
+		}
+		let mut end = start;
+		let mut used = 0;
+		while end < state.buffer.len() && used + char_width(state.buffer[end]) < available {
+			used += char_width(state.buffer[end]);
+			end += 1;
+		}
+
+		let visible: String = state.buffer[start..end].iter().collect();
+		let cursor_column = prompt_width + width(&state.buffer[start..state.cursor]);
+		let mut output = format!("\r{}{}\x1b[0K\r", state.prompt, visible);
+		if cursor_column > 0 {
+			output += &format!("\x1b[{}C", cursor_column);
+		}
+		self.write_all(output.as_bytes())
+	}
+
+	fn write_all(&mut self, mut data: &[u8]) -> CResult<()> {
+		while !data.is_empty() {
+			match self.output.write_slice(data) {
+				Ok(written) => data = &data[written..],
+				Err(CError::Interrupted) => continue,
+				Err(err) => return Err(err),
+			}
+		}
+		Ok(())
+	}
+}
+
+fn common_prefix(candidates: &[String]) -> String {
+	let mut prefix: Vec<char> = candidates[0].chars().collect();
+	for candidate in &candidates[1..] {
+		let matching = prefix.iter().zip(candidate.chars()).take_while(|(a, b)| **a == *b).count();
+		prefix.truncate(matching);
+	}
+	prefix.into_iter().collect()
+}
+
+// Skips ANSI escape sequences, which prompts often use for colors
+fn prompt_width(prompt: &str) -> usize {
+	let mut total = 0;
+	let mut chars = prompt.chars().peekable();
+	while let Some(c) = chars.next() {
+		if c == '\x1b' {
+			if chars.peek() == Some(&'[') {
+				chars.next();
+				for c in chars.by_ref() {
+					if ('@'..='~').contains(&c) {
+						break;
+					}
+				}
+			}
+			continue;
+		}
+		total += char_width(c);
+	}
+	total
+}
+
+fn width(chars: &[char]) -> usize {
+	chars.iter().map(|c| char_width(*c)).sum()
+}
+
+// Columns a character takes up in a terminal, like wcwidth but independent of
+// the current locale
+fn char_width(c: char) -> usize {
+	match c as u32 {
+		0x00..=0x1f | 0x7f..=0x9f => 0,
+		0x0300..=0x036f | 0x1ab0..=0x1aff | 0x1dc0..=0x1dff | 0x200b..=0x200f | 0x20d0..=0x20ff | 0xfe00..=0xfe0f | 0xfe20..=0xfe2f => 0,
+		0x1100..=0x115f | 0x2e80..=0x303e | 0x3041..=0x33ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xa000..=0xa4cf | 0xac00..=0xd7a3 | 0xf900..=0xfaff | 0xfe30..=0xfe4f | 0xff00..=0xff60 | 0xffe0..=0xffe6 | 0x1f300..=0x1f64f | 0x1f900..=0x1f9ff | 0x20000..=0x2fffd | 0x30000..=0x3fffd => 2,
+		_ => 1,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pipe::pipe;
+
+	fn editor_with_input() -> (LineEditor<'static>, FileDescriptor) {
+		let input = pipe().unwrap();
+		let output = pipe().unwrap();
+		(LineEditor::new(input.read_fd, output.write_fd), input.write_fd)
+	}
+
+	#[test]
+	fn lone_escape_does_not_swallow_the_next_keys() {
+		let (mut editor, mut input) = editor_with_input();
+		input.write_slice(b"\x1b").unwrap();
+		assert!(matches!(editor.read_key().unwrap(), Some(Key::Control(0x1b))));
+		input.write_slice(b"ab").unwrap();
+		assert!(matches!(editor.read_key().unwrap(), Some(Key::Char('a'))));
+		assert!(matches!(editor.read_key().unwrap(), Some(Key::Char('b'))));
+	}
+
+	#[test]
+	fn escape_followed_by_a_plain_key_keeps_the_key() {
+		let (mut editor, mut input) = editor_with_input();
+		input.write_slice(b"\x1bx").unwrap();
+		assert!(matches!(editor.read_key().unwrap(), Some(Key::Control(0x1b))));
+		assert!(matches!(editor.read_key().unwrap(), Some(Key::Char('x'))));
+	}
+
+	#[test]
+	fn escape_sequences_are_decoded() {
+		let (mut editor, mut input) = editor_with_input();
+		input.write_slice(b"\x1b[A\x1bOD\x1b[3~").unwrap();
+		assert!(matches!(editor.read_key().unwrap(), Some(Key::Up)));
+		assert!(matches!(editor.read_key().unwrap(), Some(Key::Left)));
+		assert!(matches!(editor.read_key().unwrap(), Some(Key::Delete)));
+	}
+}
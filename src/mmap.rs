@@ -0,0 +1,494 @@
+use std::{ops::{Deref, DerefMut}, ptr::NonNull};
+
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult, file::FileDescriptor};
+
+bitflags! {
+	pub struct Protection: libc::c_int {
+		const READ = libc::PROT_READ;
+		const WRITE = libc::PROT_WRITE;
+		const EXEC = libc::PROT_EXEC;
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+	Normal,
+	Random,
+	Sequential,
+	WillNeed,
+	// Leave the range out of children created by fork
+	#[cfg(target_os = "linux")]
+	DontFork,
+	#[cfg(target_os = "linux")]
+	DoFork,
+	#[cfg(target_os = "linux")]
+	Mergeable,
+	#[cfg(target_os = "linux")]
+	Unmergeable,
+	#[cfg(target_os = "linux")]
+	HugePage,
+	#[cfg(target_os = "linux")]
+	NoHugePage,
+	// Leave the range out of core dumps
+	#[cfg(target_os = "linux")]
+	DontDump,
+	#[cfg(target_os = "linux")]
+	DoDump,
+}
+
+impl From<Advice> for libc::c_int {
+	fn from(advice: Advice) -> Self {
+		match advice {
+			Advice::Normal => libc::MADV_NORMAL,
+			Advice::Random => libc::MADV_RANDOM,
+			Advice::Sequential => libc::MADV_SEQUENTIAL,
+			Advice::WillNeed => libc::MADV_WILLNEED,
+			#[cfg(target_os = "linux")]
+			Advice::DontFork => libc::MADV_DONTFORK,
+			#[cfg(target_os = "linux")]
+			Advice::DoFork => libc::MADV_DOFORK,
+			#[cfg(target_os = "linux")]
+			Advice::Mergeable => libc::MADV_MERGEABLE,
+			#[cfg(target_os = "linux")]
+			Advice::Unmergeable => libc::MADV_UNMERGEABLE,
+			#[cfg(target_os = "linux")]
+			Advice::HugePage => libc::MADV_HUGEPAGE,
+			#[cfg(target_os = "linux")]
+			Advice::NoHugePage => libc::MADV_NOHUGEPAGE,
+			#[cfg(target_os = "linux")]
+			Advice::DontDump => libc::MADV_DONTDUMP,
+			#[cfg(target_os = "linux")]
+			Advice::DoDump => libc::MADV_DODUMP,
+		}
+	}
+}
+
+// Advice that can throw away the contents of the pages, changing memory that
+// is borrowed as a slice, so it can only be given through the unsafe
+// advise_destructive methods
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestructiveAdvice {
+	// The pages may be dropped; private and anonymous ones read back as zero
+	DontNeed,
+	// The pages may be dropped whenever memory is short, unless written to
+	// again first
+	#[cfg(target_os = "linux")]
+	Free,
+	// Frees the pages and the file's storage behind them, which reads back
+	// as zero
+	#[cfg(target_os = "linux")]
+	Remove,
+}
+
+impl From<DestructiveAdvice> for libc::c_int {
+	fn from(advice: DestructiveAdvice) -> Self {
+		match advice {
+			DestructiveAdvice::DontNeed => libc::MADV_DONTNEED,
+			#[cfg(target_os = "linux")]
+			DestructiveAdvice::Free => libc::MADV_FREE,
+			#[cfg(target_os = "linux")]
+			DestructiveAdvice::Remove => libc::MADV_REMOVE,
+		}
+	}
+}
+
+pub fn page_size() -> usize {
+	unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+// The mapping itself, which starts on a page boundary; the bytes handed out
+// start offset bytes into it, so files can be mapped from any offset
+struct Mapping {
+	address: NonNull<u8>,
+	offset: usize,
+	length: usize,
+}
+
+impl Mapping {
+	fn new(length: usize, protection: Protection, flags: libc::c_int, fd: libc::c_int, file_offset: libc::off_t) -> CResult<Self> {
+		let offset = file_offset as usize % page_size();
+		// mmap rejects empty mappings, so these point nowhere and are never unmapped
+		if length == 0 {
+			return Ok(Self {
+				address: NonNull::dangling(),
+				offset: 0,
+				length: 0,
+			});
+		}
+		let address = unsafe {
+			libc::mmap(
+				std::ptr::null_mut(),
+				length + offset,
+				protection.bits(),
+				flags,
+				fd,
+				file_offset - offset as libc::off_t,
+			)
+		};
+		if address == libc::MAP_FAILED {
+			return Err(CError::new_from_errno());
+		}
+		Ok(Self {
+			address: NonNull::new(address as *mut u8).unwrap(),
+			offset,
+			length,
+		})
+	}
+
+	fn mapped_length(&self) -> usize {
+		self.offset + self.length
+	}
+
+	fn as_ptr(&self) -> *mut u8 {
+		unsafe { self.address.as_ptr().add(self.offset) }
+	}
+
+	// Widens offset..offset + length to the pages containing it, as the
+	// m* calls require a page-aligned address
+	fn page_range(&self, offset: usize, length: usize) -> CResult<(*mut libc::c_void, usize)> {
+		match offset.checked_add(length) {
+			Some(end) if end <= self.length => {},
+			_ => return Err(CError::Invalid),
+		}
+		let start = self.offset + offset;
+		let aligned_start = start - start % page_size();
+		let address = unsafe { self.address.as_ptr().add(aligned_start) };
+		Ok((address as *mut libc::c_void, length + (start - aligned_start)))
+	}
+
+	fn check(name: &str, result: libc::c_int) -> CResult<()> {
+		match result {
+			0 => Ok(()),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("{} returned {}, which is different from 0 or -1", name, bad_return),
+		}
+	}
+
+	fn flush(&self, offset: usize, length: usize, flags: libc::c_int) -> CResult<()> {
+		if length == 0 {
+			return Ok(());
+		}
+		let (address, length) = self.page_range(offset, length)?;
+		Self::check("msync", unsafe { libc::msync(address, length, flags) })
+	}
+
+	fn advise(&self, offset: usize, length: usize, advice: libc::c_int) -> CResult<()> {
+		if length == 0 {
+			return Ok(());
+		}
+		let (address, length) = self.page_range(offset, length)?;
+		Self::check("madvise", unsafe { libc::madvise(address, length, advice) })
+	}
+
+	fn protect(&self, protection: Protection) -> CResult<()> {
+		if self.length == 0 {
+			return Ok(());
+		}
+		Self::check("mprotect", unsafe { libc::mprotect(self.address.as_ptr() as *mut libc::c_void, self.mapped_length(), protection.bits()) })
+	}
+
+	fn lock(&self) -> CResult<()> {
+		Self::check("mlock", unsafe { libc::mlock(self.as_ptr() as *const libc::c_void, self.length) })
+	}
+
+	fn unlock(&self) -> CResult<()> {
+		Self::check("munlock", unsafe { libc::munlock(self.as_ptr() as *const libc::c_void, self.length) })
+	}
+
+	#[cfg(target_os = "linux")]
+	fn remap(&mut self, length: usize) -> CResult<()> {
+		// There is nothing to grow an empty mapping from
+		if self.length == 0 || length == 0 {
+			return Err(CError::Invalid);
+		}
+		let address = unsafe {
+			libc::mremap(
+				self.address.as_ptr() as *mut libc::c_void,
+				self.mapped_length(),
+				self.offset + length,
+				libc::MREMAP_MAYMOVE,
+			)
+		};
+		if address == libc::MAP_FAILED {
+			return Err(CError::new_from_errno());
+		}
+		self.address = NonNull::new(address as *mut u8).unwrap();
+		self.length = length;
+		Ok(())
+	}
+}
+
+impl Drop for Mapping {
+	fn drop(&mut self) {
+		if self.length != 0 {
+			unsafe { libc::munmap(self.address.as_ptr() as *mut libc::c_void, self.mapped_length()) };
+		}
+	}
+}
+
+fn file_length(fd: &FileDescriptor) -> CResult<usize> {
+	Ok(fd.fstat()?.size as usize)
+}
+
+// A read-only mapping
+pub struct Mmap {
+	mapping: Mapping,
+}
+
+// A writable mapping
+pub struct MmapMut {
+	mapping: Mapping,
+}
+
+// The mapped memory is only reachable through the wrapper, so these are as
+// thread safe as the slices they deref to
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+unsafe impl Send for MmapMut {}
+unsafe impl Sync for MmapMut {}
+
+// Mapping a file is unsafe because the slices handed out assume nothing else
+// changes their bytes: the caller must make sure that, for as long as the
+// mapping exists, no other process or mapping writes to the mapped range and
+// the file isn't truncated, as touching pages past its end raises SIGBUS.
+// Sealing a memfd against writes and shrinking is one way to guarantee it
+impl Mmap {
+	// Maps the whole file
+	pub unsafe fn map(fd: &FileDescriptor) -> CResult<Self> {
+		Self::map_range(fd, 0, file_length(fd)?)
+	}
+
+	pub unsafe fn map_range(fd: &FileDescriptor, offset: libc::off_t, length: usize) -> CResult<Self> {
+		Ok(Self {
+			mapping: Mapping::new(length, Protection::READ, libc::MAP_SHARED, fd.fd, offset)?,
+		})
+	}
+
+	pub fn make_mut(self) -> CResult<MmapMut> {
+		self.mapping.protect(Protection::READ | Protection::WRITE)?;
+		Ok(MmapMut {
+			mapping: self.mapping,
+		})
+	}
+
+	pub fn advise(&self, advice: Advice) -> CResult<()> {
+		self.mapping.advise(0, self.mapping.length, advice.into())
+	}
+
+	pub fn advise_range(&self, offset: usize, length: usize, advice: Advice) -> CResult<()> {
+		self.mapping.advise(offset, length, advice.into())
+	}
+
+	// The bytes in the range may change to zeros or to what the file holds,
+	// so nothing may rely on them staying the same, such as a slice taken
+	// from the mapping earlier
+	pub unsafe fn advise_destructive(&self, offset: usize, length: usize, advice: DestructiveAdvice) -> CResult<()> {
+		self.mapping.advise(offset, length, advice.into())
+	}
+
+	// Keeps the pages in RAM, so they are never written to swap
+	pub fn lock(&self) -> CResult<()> {
+		self.mapping.lock()
+	}
+
+	pub fn unlock(&self) -> CResult<()> {
+		self.mapping.unlock()
+	}
+
+	// May move the mapping; growing a file mapping past the end of the file
+	// needs the file to be extended first
+	#[cfg(target_os = "linux")]
+	pub fn remap(&mut self, length: usize) -> CResult<()> {
+		self.mapping.remap(length)
+	}
+}
+
+impl MmapMut {
+	// Writes go to the file, and are seen by every other mapping of it; the
+	// caller must uphold the same contract as for Mmap::map
+	pub unsafe fn map(fd: &FileDescriptor) -> CResult<Self> {
+		Self::map_range(fd, 0, file_length(fd)?)
+	}
+
+	pub unsafe fn map_range(fd: &FileDescriptor, offset: libc::off_t, length: usize) -> CResult<Self> {
+		Ok(Self {
+			mapping: Mapping::new(length, Protection::READ | Protection::WRITE, libc::MAP_SHARED, fd.fd, offset)?,
+		})
+	}
+
+	// Writes stay private to this mapping, and never reach the file, but
+	// pages not yet written to still show changes made to it, so this is
+	// unsafe for the same reasons as Mmap::map
+	pub unsafe fn map_copy(fd: &FileDescriptor) -> CResult<Self> {
+		Ok(Self {
+			mapping: Mapping::new(file_length(fd)?, Protection::READ | Protection::WRITE, libc::MAP_PRIVATE, fd.fd, 0)?,
+		})
+	}
+
+	// Zeroed memory, private to this process
+	pub fn anonymous(length: usize) -> CResult<Self> {
+		Ok(Self {
+			mapping: Mapping::new(length, Protection::READ | Protection::WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)?,
+		})
+	}
+
+	// Zeroed memory that stays shared with children created by fork after
+	// the mapping, so either side sees the other's writes
+	pub fn shared_anonymous(length: usize) -> CResult<Self> {
+		Ok(Self {
+			mapping: Mapping::new(length, Protection::READ | Protection::WRITE, libc::MAP_SHARED | libc::MAP_ANONYMOUS, -1, 0)?,
+		})
+	}
+
+	pub fn make_read_only(self) -> CResult<Mmap> {
+		self.mapping.protect(Protection::READ)?;
+		Ok(Mmap {
+			mapping: self.mapping,
+		})
+	}
+
+	// Waits until the changes are written to the file
+	pub fn flush(&self) -> CResult<()> {
+		self.mapping.flush(0, self.mapping.length, libc::MS_SYNC)
+	}
+
+	// Starts writing the changes to the file without waiting
+	pub fn flush_async(&self) -> CResult<()> {
+		self.mapping.flush(0, self.mapping.length, libc::MS_ASYNC)
+	}
+
+	pub fn flush_range(&self, offset: usize, length: usize) -> CResult<()> {
+		self.mapping.flush(offset, length, libc::MS_SYNC)
+	}
+
+	pub fn advise(&self, advice: Advice) -> CResult<()> {
+		self.mapping.advise(0, self.mapping.length, advice.into())
+	}
+
+	pub fn advise_range(&self, offset: usize, length: usize, advice: Advice) -> CResult<()> {
+		self.mapping.advise(offset, length, advice.into())
+	}
+
+	// The bytes in the range may change to zeros or to what the file holds,
+	// so nothing may rely on them staying the same, such as a slice taken
+	// from the mapping earlier
+	pub unsafe fn advise_destructive(&self, offset: usize, length: usize, advice: DestructiveAdvice) -> CResult<()> {
+		self.mapping.advise(offset, length, advice.into())
+	}
+
+	// Reading or writing through the mapping after taking away READ or
+	// WRITE raises SIGSEGV
+	pub unsafe fn protect(&self, protection: Protection) -> CResult<()> {
+		self.mapping.protect(protection)
+	}
+
+	pub fn lock(&self) -> CResult<()> {
+		self.mapping.lock()
+	}
+
+	pub fn unlock(&self) -> CResult<()> {
+		self.mapping.unlock()
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn remap(&mut self, length: usize) -> CResult<()> {
+		self.mapping.remap(length)
+	}
+}
+
+impl Deref for Mmap {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		unsafe { std::slice::from_raw_parts(self.mapping.as_ptr(), self.mapping.length) }
+	}
+}
+
+impl Deref for MmapMut {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		unsafe { std::slice::from_raw_parts(self.mapping.as_ptr(), self.mapping.length) }
+	}
+}
+
+impl DerefMut for MmapMut {
+	fn deref_mut(&mut self) -> &mut [u8] {
+		unsafe { std::slice::from_raw_parts_mut(self.mapping.as_ptr(), self.mapping.length) }
+	}
+}
+
+impl AsRef<[u8]> for Mmap {
+	fn as_ref(&self) -> &[u8] {
+		self
+	}
+}
+
+impl AsRef<[u8]> for MmapMut {
+	fn as_ref(&self) -> &[u8] {
+		self
+	}
+}
+
+impl AsMut<[u8]> for MmapMut {
+	fn as_mut(&mut self) -> &mut [u8] {
+		self
+	}
+}
+
+impl std::fmt::Debug for Mmap {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Mmap")
+			.field("address", &self.mapping.as_ptr())
+			.field("length", &self.mapping.length)
+			.finish()
+	}
+}
+
+impl std::fmt::Debug for MmapMut {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("MmapMut")
+			.field("address", &self.mapping.as_ptr())
+			.field("length", &self.mapping.length)
+			.finish()
+	}
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use std::ffi::CString;
+
+	use super::*;
+	use crate::file::memfd::{memfd_create, MemfdFlags};
+
+	#[test]
+	fn writes_through_a_mapping_reach_the_file() {
+		let mut fd = memfd_create(CString::new("mmap-test").unwrap(), MemfdFlags::CLOEXEC).unwrap();
+		fd.write_slice(&[0; 16]).unwrap();
+		// Only this test has the memfd, so nothing else changes it
+		let mut mapping = unsafe { MmapMut::map(&fd) }.unwrap();
+		mapping[..5].copy_from_slice(b"hello");
+		mapping.flush().unwrap();
+		let copy = unsafe { Mmap::map_range(&fd, 0, 5) }.unwrap();
+		assert_eq!(&copy[..], b"hello");
+	}
+
+	#[test]
+	fn ranges_past_the_end_are_refused() {
+		let mapping = MmapMut::anonymous(page_size()).unwrap();
+		assert!(matches!(mapping.flush_range(1, page_size()), Err(CError::Invalid)));
+		assert!(matches!(mapping.advise_range(usize::MAX, 2, Advice::Normal), Err(CError::Invalid)));
+		mapping.advise_range(0, page_size(), Advice::Normal).unwrap();
+	}
+
+	#[test]
+	fn dont_need_zeroes_private_anonymous_pages() {
+		let mut mapping = MmapMut::anonymous(page_size()).unwrap();
+		mapping[0] = 1;
+		// Nothing else holds on to the bytes
+		unsafe { mapping.advise_destructive(0, page_size(), DestructiveAdvice::DontNeed) }.unwrap();
+		assert_eq!(mapping[0], 0);
+	}
+}
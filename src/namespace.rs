@@ -0,0 +1,285 @@
+use std::{convert::TryInto, ffi::CString, os::unix::io::{AsFd, AsRawFd}};
+
+use crate::{c_error::CError, c_result::CResult, file::{open::{open_with_flags, flags::{O_RDONLY, O_WRONLY, O_CLOEXEC}}, FileDescriptor}, fork::{fork, ForkResult}, pipe::{pipe_with_flags, PipeResult}, wait::waitpid};
+
+pub fn unshare(flags: libc::c_int) -> CResult<()> {
+	match unsafe { libc::unshare(flags) } {
+		0 => Ok(()),
+		-1 => Err(CError::new_from_errno()),
+		bad_return => panic!("unshare returned {}, which is different from 0 or -1", bad_return),
+	}
+}
+
+// Moves the calling thread into the namespace referred to by fd.
+// nstype may be 0 to allow any namespace type.
+pub fn setns<Fd: AsFd>(fd: Fd, nstype: libc::c_int) -> CResult<()> {
+	match unsafe { libc::setns(fd.as_fd().as_raw_fd(), nstype) } {
+		0 => Ok(()),
+		-1 => Err(CError::new_from_errno()),
+		bad_return => panic!("setns returned {}, which is different from 0 or -1", bad_return),
+	}
+}
+
+// Like fork::fork, but the child starts in the new namespaces given by flags
+// while the caller stays where it is. The child unshares them itself and
+// reports a failure back through a pipe, so it is returned here as usual.
+// A new PID namespace is only entered by the children of whoever unshares
+// it, so with CLONE_NEWPID the child forks once more: the pid returned is
+// that of the process in between, which exits the same way as the one in
+// the namespace
+pub fn clone(flags: libc::c_int) -> CResult<ForkResult> {
+	let PipeResult { read_fd: mut failure, write_fd: mut report } = pipe_with_flags(O_CLOEXEC)?;
+	match fork()? {
+		ForkResult::Child => {
+			drop(failure);
+			let result = unshare(flags).and_then(|()| match flags & flags::CLONE_NEWPID {
+				0 => Ok(ForkResult::Child),
+				_ => fork(),
+			});
+			match result {
+				Ok(ForkResult::Child) => {
+					drop(report);
+					Ok(ForkResult::Child)
+				},
+				Ok(ForkResult::Parent(pid)) => {
+					drop(report);
+					exit_like(pid)
+				},
+				Err(err) => {
+					let _ = report.write_slice(&libc::c_int::from(err).to_ne_bytes());
+					unsafe { libc::_exit(127) }
+				},
+			}
+		},
+		ForkResult::Parent(pid) => {
+			drop(report);
+			let errno = loop {
+				match failure.read_bytes(std::mem::size_of::<libc::c_int>()) {
+					Err(CError::Interrupted) => continue,
+					result => break result?,
+				}
+			};
+			if errno.is_empty() {
+				return Ok(ForkResult::Parent(pid));
+			}
+			waitpid(pid)?;
+			Err(CError::from(libc::c_int::from_ne_bytes(errno.as_slice().try_into().unwrap())))
+		},
+	}
+}
+
+// Waits for pid and exits with its exit status, or dies by the same signal
+fn exit_like(pid: libc::pid_t) -> ! {
+	let status = loop {
+		match waitpid(pid) {
+			Err(CError::Interrupted) => continue,
+			Ok(result) => break result.status,
+			Err(_) => unsafe { libc::_exit(127) },
+		}
+	};
+	if status.exited_normally() {
+		unsafe { libc::_exit(status.exit_status() as libc::c_int) }
+	}
+	let signal = status.terminating_signal();
+	unsafe {
+		libc::signal(signal, libc::SIG_DFL);
+		libc::raise(signal);
+		libc::_exit(128 + signal)
+	}
+}
+
+pub fn open_namespace(pid: Option<libc::pid_t>, namespace: NamespaceType) -> CResult<FileDescriptor> {
+	open_with_flags(proc_path(pid, &format!("ns/{}", namespace.proc_name())), O_RDONLY | O_CLOEXEC)
+}
+
+pub fn enter_namespace_of(pid: libc::pid_t, namespace: NamespaceType) -> CResult<()> {
+	let fd = open_namespace(Some(pid), namespace)?;
+	setns(&fd, namespace.into())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamespaceType {
+	Cgroup,
+	Ipc,
+	Mount,
+	Network,
+	Pid,
+	User,
+	Uts,
+}
+
+impl NamespaceType {
+	pub fn proc_name(&self) -> &'static str {
+		match self {
+			NamespaceType::Cgroup => "cgroup",
+			NamespaceType::Ipc => "ipc",
+			NamespaceType::Mount => "mnt",
+			NamespaceType::Network => "net",
+			NamespaceType::Pid => "pid",
+			NamespaceType::User => "user",
+			NamespaceType::Uts => "uts",
+		}
+	}
+}
+
+impl From<NamespaceType> for libc::c_int {
+	fn from(namespace: NamespaceType) -> Self {
+		match namespace {
+			NamespaceType::Cgroup => flags::CLONE_NEWCGROUP,
+			NamespaceType::Ipc => flags::CLONE_NEWIPC,
+			NamespaceType::Mount => flags::CLONE_NEWNS,
+			NamespaceType::Network => flags::CLONE_NEWNET,
+			NamespaceType::Pid => flags::CLONE_NEWPID,
+			NamespaceType::User => flags::CLONE_NEWUSER,
+			NamespaceType::Uts => flags::CLONE_NEWUTS,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMap {
+	pub inside: u32,
+	pub outside: u32,
+	pub count: u32,
+}
+
+impl IdMap {
+	pub fn single(inside: u32, outside: u32) -> Self {
+		Self {
+			inside,
+			outside,
+			count: 1,
+		}
+	}
+}
+
+// pid is None for the calling process
+pub fn write_uid_map(pid: Option<libc::pid_t>, maps: &[IdMap]) -> CResult<()> {
+	write_id_map(pid, "uid_map", maps)
+}
+
+pub fn write_gid_map(pid: Option<libc::pid_t>, maps: &[IdMap]) -> CResult<()> {
+	write_id_map(pid, "gid_map", maps)
+}
+
+// Must be called before write_gid_map by an unprivileged process
+pub fn deny_setgroups(pid: Option<libc::pid_t>) -> CResult<()> {
+	write_proc_file(pid, "setgroups", "deny")
+}
+
+// Maps the given outside uid and gid to root inside the user namespace of pid
+pub fn map_root(pid: Option<libc::pid_t>, uid: libc::uid_t, gid: libc::gid_t) -> CResult<()> {
+	deny_setgroups(pid)?;
+	write_uid_map(pid, &[IdMap::single(0, uid)])?;
+	write_gid_map(pid, &[IdMap::single(0, gid)])
+}
+
+// Unshares a new user namespace (plus any other namespaces in flags) in
+// which the caller appears as root
+pub fn unshare_as_root(flags: libc::c_int) -> CResult<()> {
+	let uid = unsafe { libc::getuid() };
+	let gid = unsafe { libc::getgid() };
+	unshare(flags | libc::CLONE_NEWUSER)?;
+	map_root(None, uid, gid)
+}
+
+fn write_id_map(pid: Option<libc::pid_t>, file: &str, maps: &[IdMap]) -> CResult<()> {
+	let contents: String = maps.iter().map(
+		|map| format!("{} {} {}\n", map.inside, map.outside, map.count)
+	).collect();
+	write_proc_file(pid, file, &contents)
+}
+
+fn write_proc_file(pid: Option<libc::pid_t>, file: &str, contents: &str) -> CResult<()> {
+	let mut fd = open_with_flags(proc_path(pid, file), O_WRONLY | O_CLOEXEC)?;
+	// These files must be written in a single write call
+	let bytes_written = fd.write_slice(contents.as_bytes())?;
+	if bytes_written != contents.len() {
+		return Err(CError::Invalid);
+	}
+	Ok(())
+}
+
+fn proc_path(pid: Option<libc::pid_t>, file: &str) -> CString {
+	let path = match pid {
+		Some(pid) => format!("/proc/{}/{}", pid, file),
+		None => format!("/proc/self/{}", file),
+	};
+	CString::new(path).unwrap()	// Formatted numbers and names never contain NUL
+}
+
+pub mod flags {
+	pub use libc::{
+		CLONE_NEWCGROUP,
+		CLONE_NEWIPC,
+		CLONE_NEWNET,
+		CLONE_NEWNS,
+		CLONE_NEWPID,
+		CLONE_NEWUSER,
+		CLONE_NEWUTS,
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Exit status of a test process that can't create namespaces here
+	const UNPRIVILEGED: i32 = 77;
+
+	// Runs test in a process of its own, so the test harness keeps its
+	// namespaces, and returns its exit status
+	fn in_child_process<Test: FnOnce() -> i32>(test: Test) -> i32 {
+		match fork().unwrap() {
+			ForkResult::Child => {
+				let status = test();
+				unsafe { libc::_exit(status) }
+			},
+			ForkResult::Parent(pid) => {
+				let result = waitpid(pid).unwrap();
+				assert!(result.status.exited_normally());
+				result.status.exit_status() as i32
+			},
+		}
+	}
+
+	#[test]
+	fn clone_starts_the_child_in_a_new_pid_namespace() {
+		let status = in_child_process(|| match clone(flags::CLONE_NEWPID) {
+			Ok(ForkResult::Child) => unsafe { libc::_exit(libc::getpid()) },
+			Ok(ForkResult::Parent(pid)) => match waitpid(pid) {
+				Ok(result) if result.status.exited_normally() => result.status.exit_status() as i32,
+				_ => 1,
+			},
+			Err(_) => UNPRIVILEGED,
+		});
+		// The first process in a PID namespace is its init, with pid 1
+		if status != UNPRIVILEGED {
+			assert_eq!(status, 1);
+		}
+	}
+
+	#[test]
+	fn clone_leaves_the_caller_in_its_namespaces() {
+		let status = in_child_process(|| {
+			let before = std::fs::read_link("/proc/self/ns/uts").unwrap();
+			match clone(flags::CLONE_NEWUTS) {
+				Ok(ForkResult::Child) => unsafe { libc::_exit(0) },
+				Ok(ForkResult::Parent(pid)) => {
+					waitpid(pid).unwrap();
+					(std::fs::read_link("/proc/self/ns/uts").unwrap() != before) as i32
+				},
+				Err(_) => UNPRIVILEGED,
+			}
+		});
+		if status != UNPRIVILEGED {
+			assert_eq!(status, 0);
+		}
+	}
+
+	#[test]
+	fn clone_returns_the_childs_unshare_error() {
+		// unshare doesn't take CLONE_PARENT_SETTID
+		assert!(matches!(clone(libc::CLONE_PARENT_SETTID), Err(CError::Invalid)));
+	}
+}
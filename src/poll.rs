@@ -0,0 +1,467 @@
+use std::time::{Duration, Instant};
+#[cfg(target_os = "linux")]
+use std::{collections::HashMap, os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd}};
+
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult, file::FileDescriptor};
+
+bitflags! {
+	pub struct Interest: u32 {
+		const READABLE = 0b0001;
+		const WRITABLE = 0b0010;
+		// Out-of-band data, or a change on a pty master in packet mode
+		const PRIORITY = 0b0100;
+		// The other end shut down writing, reported without reading to EOF
+		#[cfg(target_os = "linux")]
+		const READ_HANGUP = 0b1000;
+	}
+}
+
+bitflags! {
+	pub struct Readiness: u32 {
+		const READABLE = 0b0000_0001;
+		const WRITABLE = 0b0000_0010;
+		const PRIORITY = 0b0000_0100;
+		#[cfg(target_os = "linux")]
+		const READ_HANGUP = 0b0000_1000;
+		// Reported whether asked for or not
+		const HANGUP = 0b0001_0000;
+		const ERROR = 0b0010_0000;
+		// The descriptor isn't open; only poll reports this
+		const INVALID = 0b0100_0000;
+	}
+}
+
+impl From<Interest> for libc::c_short {
+	fn from(interest: Interest) -> Self {
+		let mut events = 0;
+		if interest.contains(Interest::READABLE) {
+			events |= libc::POLLIN;
+		}
+		if interest.contains(Interest::WRITABLE) {
+			events |= libc::POLLOUT;
+		}
+		if interest.contains(Interest::PRIORITY) {
+			events |= libc::POLLPRI;
+		}
+		#[cfg(target_os = "linux")]
+		if interest.contains(Interest::READ_HANGUP) {
+			events |= libc::POLLRDHUP;
+		}
+		events
+	}
+}
+
+impl From<libc::c_short> for Readiness {
+	fn from(revents: libc::c_short) -> Self {
+		let mut readiness = Readiness::empty();
+		let mut set = |event: libc::c_short, flag: Readiness| {
+			if revents & event != 0 {
+				readiness |= flag;
+			}
+		};
+		set(libc::POLLIN, Readiness::READABLE);
+		set(libc::POLLOUT, Readiness::WRITABLE);
+		set(libc::POLLPRI, Readiness::PRIORITY);
+		#[cfg(target_os = "linux")]
+		set(libc::POLLRDHUP, Readiness::READ_HANGUP);
+		set(libc::POLLHUP, Readiness::HANGUP);
+		set(libc::POLLERR, Readiness::ERROR);
+		set(libc::POLLNVAL, Readiness::INVALID);
+		readiness
+	}
+}
+
+#[cfg(target_os = "linux")]
+impl From<Interest> for u32 {
+	fn from(interest: Interest) -> Self {
+		let mut events = 0;
+		if interest.contains(Interest::READABLE) {
+			events |= libc::EPOLLIN;
+		}
+		if interest.contains(Interest::WRITABLE) {
+			events |= libc::EPOLLOUT;
+		}
+		if interest.contains(Interest::PRIORITY) {
+			events |= libc::EPOLLPRI;
+		}
+		if interest.contains(Interest::READ_HANGUP) {
+			events |= libc::EPOLLRDHUP;
+		}
+		events as u32
+	}
+}
+
+#[cfg(target_os = "linux")]
+impl From<u32> for Readiness {
+	fn from(events: u32) -> Self {
+		let events = events as libc::c_int;
+		let mut readiness = Readiness::empty();
+		let mut set = |event: libc::c_int, flag: Readiness| {
+			if events & event != 0 {
+				readiness |= flag;
+			}
+		};
+		set(libc::EPOLLIN, Readiness::READABLE);
+		set(libc::EPOLLOUT, Readiness::WRITABLE);
+		set(libc::EPOLLPRI, Readiness::PRIORITY);
+		set(libc::EPOLLRDHUP, Readiness::READ_HANGUP);
+		set(libc::EPOLLHUP, Readiness::HANGUP);
+		set(libc::EPOLLERR, Readiness::ERROR);
+		readiness
+	}
+}
+
+// Milliseconds left until deadline, rounded up so that we don't spin right
+// before it; -1 waits forever
+fn timeout_millis(deadline: Option<Instant>) -> libc::c_int {
+	match deadline {
+		Some(deadline) => {
+			let now = Instant::now();
+			if now >= deadline {
+				0
+			}
+			else {
+				let remaining = (deadline - now).as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+				remaining.saturating_add(1)
+			}
+		},
+		None => -1,
+	}
+}
+
+// Waits until at least one of fds is ready or timeout elapses, and returns
+// the readiness of each, in the same order; None waits forever
+pub fn poll(fds: &[(&FileDescriptor, Interest)], timeout: Option<Duration>) -> CResult<Vec<Readiness>> {
+	let mut poll_fds: Vec<libc::pollfd> = fds.iter()
+		.map(|(fd, interest)| libc::pollfd { fd: fd.fd, events: (*interest).into(), revents: 0 })
+		.collect();
+	let deadline = timeout.map(|timeout| Instant::now() + timeout);
+	loop {
+		match unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, timeout_millis(deadline)) } {
+			-1 => match CError::new_from_errno() {
+				CError::Interrupted => continue,
+				err => return Err(err),
+			},
+			_ => return Ok(poll_fds.iter().map(|poll_fd| Readiness::from(poll_fd.revents)).collect()),
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+	// Reported on every wait for as long as the descriptor stays ready
+	Level,
+	// Reported once each time the descriptor becomes ready, so it must be
+	// read or written until Again before waiting again
+	Edge,
+	// Reported once, after which the descriptor is disabled until modify
+	// is called on it
+	OneShot,
+}
+
+#[cfg(target_os = "linux")]
+impl Trigger {
+	fn flags(self) -> u32 {
+		(match self {
+			Trigger::Level => 0,
+			Trigger::Edge => libc::EPOLLET,
+			Trigger::OneShot => libc::EPOLLONESHOT,
+		}) as u32
+	}
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+	pub token: u64,
+	pub readiness: Readiness,
+}
+
+// A buffer wait fills in, kept around to avoid allocating on every wait
+#[cfg(target_os = "linux")]
+pub struct Events {
+	buffer: Vec<libc::epoll_event>,
+	length: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl Events {
+	pub fn with_capacity(capacity: usize) -> Self {
+		Self {
+			buffer: vec![libc::epoll_event { events: 0, u64: 0 }; capacity.max(1)],
+			length: 0,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.length
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.length == 0
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.buffer.len()
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+		self.buffer[..self.length].iter().map(|event| Event {
+			token: event.u64,
+			readiness: Readiness::from(event.events),
+		})
+	}
+}
+
+#[cfg(target_os = "linux")]
+pub struct Epoll {
+	fd: FileDescriptor,
+}
+
+#[cfg(target_os = "linux")]
+impl Epoll {
+	// The epoll descriptor is not inherited across exec
+	pub fn new() -> CResult<Self> {
+		match unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) } {
+			-1 => Err(CError::new_from_errno()),
+			fd => Ok(Self {
+				fd: unsafe { FileDescriptor::from_unowned(fd) },
+			}),
+		}
+	}
+
+	fn control(&self, operation: libc::c_int, fd: RawFd, event: Option<libc::epoll_event>) -> CResult<()> {
+		// Kernels before 2.6.9 want an event even for EPOLL_CTL_DEL
+		let mut event = event.unwrap_or(libc::epoll_event { events: 0, u64: 0 });
+		match unsafe { libc::epoll_ctl(self.fd.fd, operation, fd, &mut event) } {
+			0 => Ok(()),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("epoll_ctl returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+
+	// token is handed back in every Event for fd
+	pub fn add<Fd: AsFd>(&self, fd: Fd, interest: Interest, trigger: Trigger, token: u64) -> CResult<()> {
+		let event = libc::epoll_event { events: u32::from(interest) | trigger.flags(), u64: token };
+		self.control(libc::EPOLL_CTL_ADD, fd.as_fd().as_raw_fd(), Some(event))
+	}
+
+	pub fn modify<Fd: AsFd>(&self, fd: Fd, interest: Interest, trigger: Trigger, token: u64) -> CResult<()> {
+		let event = libc::epoll_event { events: u32::from(interest) | trigger.flags(), u64: token };
+		self.control(libc::EPOLL_CTL_MOD, fd.as_fd().as_raw_fd(), Some(event))
+	}
+
+	// Closing every copy of a descriptor removes it as well
+	pub fn delete<Fd: AsFd>(&self, fd: Fd) -> CResult<()> {
+		self.control(libc::EPOLL_CTL_DEL, fd.as_fd().as_raw_fd(), None)
+	}
+
+	// Fills events with what is ready, waiting up to timeout for something
+	// to be; returns how many there are, 0 if the wait timed out
+	pub fn wait(&self, events: &mut Events, timeout: Option<Duration>) -> CResult<usize> {
+		let deadline = timeout.map(|timeout| Instant::now() + timeout);
+		loop {
+			let capacity = events.buffer.len().min(libc::c_int::MAX as usize) as libc::c_int;
+			match unsafe { libc::epoll_wait(self.fd.fd, events.buffer.as_mut_ptr(), capacity, timeout_millis(deadline)) } {
+				-1 => match CError::new_from_errno() {
+					CError::Interrupted => continue,
+					err => {
+						events.length = 0;
+						return Err(err);
+					},
+				},
+				ready => {
+					events.length = ready as usize;
+					return Ok(events.length);
+				},
+			}
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+impl AsRawFd for Epoll {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.fd
+	}
+}
+
+// An epoll descriptor is itself readable when any of its descriptors is ready
+#[cfg(target_os = "linux")]
+impl AsFd for Epoll {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.fd.as_fd()
+	}
+}
+
+// What an EventLoop should do after a callback returns
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+	Continue,
+	// Stop watching this descriptor and drop its callback
+	Remove,
+	// Return from run, leaving every descriptor registered
+	Stop,
+}
+
+#[cfg(target_os = "linux")]
+pub type EventCallback<'a> = Box<dyn FnMut(Readiness) -> Action + 'a>;
+
+#[cfg(target_os = "linux")]
+struct Registration<'a> {
+	// Kept so the descriptor stays open for as long as it is watched
+	source: Box<dyn AsFd + 'a>,
+	callback: EventCallback<'a>,
+}
+
+// Calls back on a single thread whenever one of its descriptors is ready,
+// level-triggered, so pipes, signalfds, timerfds and pidfds can be watched
+// together
+#[cfg(target_os = "linux")]
+pub struct EventLoop<'a> {
+	epoll: Epoll,
+	events: Events,
+	registrations: HashMap<u64, Registration<'a>>,
+	next_token: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl<'a> EventLoop<'a> {
+	pub fn new() -> CResult<Self> {
+		Ok(Self {
+			epoll: Epoll::new()?,
+			events: Events::with_capacity(64),
+			registrations: HashMap::new(),
+			next_token: 0,
+		})
+	}
+
+	// source may be a borrowed or an owned descriptor; an owned one is
+	// closed once it is removed. Returns a token for deregister
+	pub fn register<Source, Callback>(&mut self, source: Source, interest: Interest, callback: Callback) -> CResult<u64>
+	where
+		Source: AsFd + 'a,
+		Callback: FnMut(Readiness) -> Action + 'a,
+	{
+		let token = self.next_token;
+		self.epoll.add(&source, interest, Trigger::Level, token)?;
+		self.next_token += 1;
+		self.registrations.insert(token, Registration {
+			source: Box::new(source),
+			callback: Box::new(callback),
+		});
+		Ok(token)
+	}
+
+	pub fn deregister(&mut self, token: u64) -> CResult<()> {
+		match self.registrations.remove(&token) {
+			Some(registration) => self.epoll.delete(registration.source.as_fd()),
+			None => Err(CError::NotFound),
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.registrations.is_empty()
+	}
+
+	// Waits up to timeout for descriptors to become ready and calls their
+	// callbacks; returns whether a callback asked to stop
+	pub fn run_once(&mut self, timeout: Option<Duration>) -> CResult<bool> {
+		self.epoll.wait(&mut self.events, timeout)?;
+		let ready: Vec<Event> = self.events.iter().collect();
+		let mut stop = false;
+		for event in ready {
+			// Only a token's own callback removes it and epoll reports each
+			// descriptor once per wait, so it should still be registered;
+			// skipping it keeps a stale event from panicking if it isn't
+			let registration = match self.registrations.get_mut(&event.token) {
+				Some(registration) => registration,
+				None => continue,
+			};
+			match (registration.callback)(event.readiness) {
+				Action::Continue => {},
+				Action::Remove => self.deregister(event.token)?,
+				Action::Stop => stop = true,
+			}
+		}
+		Ok(stop)
+	}
+
+	// Dispatches events until a callback returns Stop or nothing is left
+	// to watch
+	pub fn run(&mut self) -> CResult<()> {
+		while !self.is_empty() {
+			if self.run_once(None)? {
+				break;
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pipe::pipe;
+
+	#[test]
+	fn poll_reports_a_pipe_readable_once_written_to() {
+		let mut pipe = pipe().unwrap();
+		let ready = poll(&[(&pipe.read_fd, Interest::READABLE)], Some(Duration::from_millis(0))).unwrap();
+		assert_eq!(ready, vec![Readiness::empty()]);
+		pipe.write_fd.write_slice(b"x").unwrap();
+		let ready = poll(&[(&pipe.read_fd, Interest::READABLE)], Some(Duration::from_millis(0))).unwrap();
+		assert_eq!(ready, vec![Readiness::READABLE]);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn epoll_reports_an_edge_once() {
+		let mut pipe = pipe().unwrap();
+		let epoll = Epoll::new().unwrap();
+		epoll.add(&pipe.read_fd, Interest::READABLE, Trigger::Edge, 7).unwrap();
+		let mut events = Events::with_capacity(4);
+		pipe.write_fd.write_slice(b"x").unwrap();
+		assert_eq!(epoll.wait(&mut events, Some(Duration::from_millis(0))).unwrap(), 1);
+		assert_eq!(events.iter().collect::<Vec<Event>>(), vec![Event { token: 7, readiness: Readiness::READABLE }]);
+		// Still unread, but no new data arrived
+		assert_eq!(epoll.wait(&mut events, Some(Duration::from_millis(0))).unwrap(), 0);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn event_loop_calls_back_and_honours_remove() {
+		let calls = std::cell::Cell::new(0);
+		let mut pipe = pipe().unwrap();
+		let mut event_loop = EventLoop::new().unwrap();
+		event_loop.register(pipe.read_fd, Interest::READABLE, |readiness| {
+			assert!(readiness.contains(Readiness::READABLE));
+			calls.set(calls.get() + 1);
+			Action::Remove
+		}).unwrap();
+		pipe.write_fd.write_slice(b"x").unwrap();
+		event_loop.run().unwrap();
+		assert_eq!(calls.get(), 1);
+		assert!(event_loop.is_empty());
+		// The byte was never read, so a level-triggered watch would fire again
+		assert!(!event_loop.run_once(Some(Duration::from_millis(0))).unwrap());
+		assert_eq!(calls.get(), 1);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn event_loop_stops_when_asked_and_keeps_the_descriptor() {
+		let mut pipe = pipe().unwrap();
+		let mut event_loop = EventLoop::new().unwrap();
+		let token = event_loop.register(pipe.read_fd, Interest::READABLE, |_| Action::Stop).unwrap();
+		pipe.write_fd.write_slice(b"x").unwrap();
+		event_loop.run().unwrap();
+		assert!(!event_loop.is_empty());
+		event_loop.deregister(token).unwrap();
+		assert!(matches!(event_loop.deregister(token), Err(CError::NotFound)));
+	}
+}
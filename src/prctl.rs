@@ -0,0 +1,136 @@
+use std::ffi::CStr;
+
+use crate::{c_error::CError, c_result::CResult};
+
+// The kernel limits thread names to 16 bytes, including the terminating NUL
+pub const NAME_BUFFER_SIZE: usize = 16;
+
+// Apart from get_name, none of these functions allocate, so they are safe to
+// call between fork::fork and exec::exec*; get_name_into is the alternative
+// to get_name there
+pub(crate) fn prctl(option: libc::c_int, arg2: libc::c_ulong) -> CResult<libc::c_int> {
+	match unsafe { libc::prctl(option, arg2, 0 as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong) } {
+		-1 => Err(CError::new_from_errno()),
+		any => Ok(any),
+	}
+}
+
+pub fn set_parent_death_signal(signal: libc::c_int) -> CResult<()> {
+	prctl(libc::PR_SET_PDEATHSIG, signal as libc::c_ulong).map(|_| ())
+}
+
+// Sets the parent death signal, then fails with NoSuchProcess if the parent
+// (whose pid was recorded before forking) died before the signal was set
+pub fn set_parent_death_signal_checked(signal: libc::c_int, parent_pid: libc::pid_t) -> CResult<()> {
+	set_parent_death_signal(signal)?;
+	if unsafe { libc::getppid() } != parent_pid {
+		return Err(CError::NoSuchProcess);
+	}
+	Ok(())
+}
+
+pub fn get_parent_death_signal() -> CResult<libc::c_int> {
+	let mut signal: libc::c_int = 0;
+	prctl(libc::PR_GET_PDEATHSIG, &mut signal as *mut libc::c_int as libc::c_ulong)?;
+	Ok(signal)
+}
+
+// Names longer than 15 bytes are truncated
+pub fn set_name(name: &str) -> CResult<()> {
+	let mut buffer = [0u8; NAME_BUFFER_SIZE];
+	for (destination, byte) in buffer.iter_mut().zip(name.bytes().take(NAME_BUFFER_SIZE - 1)) {
+		if byte == 0 {
+			return Err(CError::Invalid);
+		}
+		*destination = byte;
+	}
+	prctl(libc::PR_SET_NAME, buffer.as_ptr() as libc::c_ulong).map(|_| ())
+}
+
+pub fn get_name() -> CResult<String> {
+	let mut buffer = [0u8; NAME_BUFFER_SIZE];
+	let name = get_name_into(&mut buffer)?;
+	Ok(name.to_string_lossy().into_owned())
+}
+
+// Fills buffer instead of allocating
+pub fn get_name_into(buffer: &mut [u8; NAME_BUFFER_SIZE]) -> CResult<&CStr> {
+	prctl(libc::PR_GET_NAME, buffer.as_mut_ptr() as libc::c_ulong)?;
+	// The kernel always NUL-terminates the name
+	Ok(unsafe { CStr::from_ptr(buffer.as_ptr() as *const libc::c_char) })
+}
+
+// no_new_privs cannot be unset once set
+pub fn set_no_new_privs() -> CResult<()> {
+	prctl(libc::PR_SET_NO_NEW_PRIVS, 1).map(|_| ())
+}
+
+pub fn get_no_new_privs() -> CResult<bool> {
+	prctl(libc::PR_GET_NO_NEW_PRIVS, 0).map(|value| value == 1)
+}
+
+pub fn set_dumpable(dumpable: bool) -> CResult<()> {
+	prctl(libc::PR_SET_DUMPABLE, dumpable as libc::c_ulong).map(|_| ())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dumpable {
+	No,
+	Yes,
+	// Core dumps are only readable by root, as set for setuid programs by
+	// fs.suid_dumpable = 2; set_dumpable can't select this
+	RootOnly,
+}
+
+pub fn get_dumpable() -> CResult<Dumpable> {
+	match prctl(libc::PR_GET_DUMPABLE, 0)? {
+		0 => Ok(Dumpable::No),
+		1 => Ok(Dumpable::Yes),
+		2 => Ok(Dumpable::RootOnly),
+		bad_return => panic!("PR_GET_DUMPABLE returned {}, which is different from 0, 1 or 2", bad_return),
+	}
+}
+
+// A slack of 0 resets the thread to its default timer slack
+pub fn set_timer_slack(nanoseconds: libc::c_ulong) -> CResult<()> {
+	prctl(libc::PR_SET_TIMERSLACK, nanoseconds).map(|_| ())
+}
+
+pub fn get_timer_slack() -> CResult<libc::c_ulong> {
+	prctl(libc::PR_GET_TIMERSLACK, 0).map(|slack| slack as libc::c_ulong)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{fork::{fork, ForkResult}, wait::waitpid};
+
+	#[test]
+	fn dumpable_round_trips() {
+		// The flag is per process, so it is changed in a child rather than
+		// under the other tests running in this one
+		match fork().unwrap() {
+			ForkResult::Child => {
+				let round_trips = set_dumpable(false).is_ok()
+					&& matches!(get_dumpable(), Ok(Dumpable::No))
+					&& set_dumpable(true).is_ok()
+					&& matches!(get_dumpable(), Ok(Dumpable::Yes));
+				unsafe { libc::_exit(if round_trips { 0 } else { 1 }) }
+			},
+			ForkResult::Parent(pid) => {
+				let result = waitpid(pid).unwrap();
+				assert!(result.status.exited_normally());
+				assert_eq!(result.status.exit_status(), 0);
+			},
+		}
+	}
+
+	#[test]
+	fn name_is_read_into_a_buffer() {
+		// Test threads are named after the test, so this only renames this one
+		set_name("prctl-test-name-too-long").unwrap();
+		let mut buffer = [0u8; NAME_BUFFER_SIZE];
+		assert_eq!(get_name_into(&mut buffer).unwrap().to_bytes(), b"prctl-test-name");
+		assert_eq!(get_name().unwrap(), "prctl-test-name");
+	}
+}
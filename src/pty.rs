@@ -0,0 +1,216 @@
+use std::{ffi::{CStr, CString}, os::unix::io::{AsFd, AsRawFd}};
+
+use crate::{c_error::CError, c_result::CResult, file::{open::{open_with_flags, flags::{O_CLOEXEC, O_NOCTTY, O_RDWR}}, FileDescriptor}, fork::{exit_child_on_error, fork, ForkResult}};
+
+#[cfg(target_os = "linux")]
+static PTS_NAME_BUFFER_SIZE: usize = 128;
+
+pub struct Pty {
+	pub master: FileDescriptor,
+	pub slave: FileDescriptor,
+	pub slave_name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WindowSize {
+	pub rows: u16,
+	pub columns: u16,
+	pub x_pixels: u16,
+	pub y_pixels: u16,
+}
+
+impl From<libc::winsize> for WindowSize {
+	fn from(size: libc::winsize) -> Self {
+		Self {
+			rows: size.ws_row,
+			columns: size.ws_col,
+			x_pixels: size.ws_xpixel,
+			y_pixels: size.ws_ypixel,
+		}
+	}
+}
+
+impl From<WindowSize> for libc::winsize {
+	fn from(size: WindowSize) -> Self {
+		Self {
+			ws_row: size.rows,
+			ws_col: size.columns,
+			ws_xpixel: size.x_pixels,
+			ws_ypixel: size.y_pixels,
+		}
+	}
+}
+
+pub fn posix_openpt(flags: libc::c_int) -> CResult<FileDescriptor> {
+	match unsafe { libc::posix_openpt(flags) } {
+		-1 => Err(CError::new_from_errno()),
+		fd => Ok(unsafe { FileDescriptor::from_unowned(fd) }),
+	}
+}
+
+pub fn grantpt(master: &FileDescriptor) -> CResult<()> {
+	match unsafe { libc::grantpt(master.fd) } {
+		0 => Ok(()),
+		-1 => Err(CError::new_from_errno()),
+		bad_return => panic!("grantpt returned {}, which is different from 0 or -1", bad_return),
+	}
+}
+
+pub fn unlockpt(master: &FileDescriptor) -> CResult<()> {
+	match unsafe { libc::unlockpt(master.fd) } {
+		0 => Ok(()),
+		-1 => Err(CError::new_from_errno()),
+		bad_return => panic!("unlockpt returned {}, which is different from 0 or -1", bad_return),
+	}
+}
+
+#[cfg(target_os = "linux")]
+pub fn ptsname(master: &FileDescriptor) -> CResult<String> {
+	let mut buffer = vec![0 as libc::c_char; PTS_NAME_BUFFER_SIZE];
+	match unsafe { libc::ptsname_r(master.fd, buffer.as_mut_ptr(), buffer.len()) } {
+		0 => Ok(unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_string_lossy().into_owned()),
+		errno => Err(CError::from(errno)),
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn ptsname(master: &FileDescriptor) -> CResult<String> {
+	// ptsname isn't thread safe, but it's the only portable option
+	let name = unsafe { libc::ptsname(master.fd) };
+	if name.is_null() {
+		Err(CError::new_from_errno())
+	}
+	else {
+		Ok(unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned())
+	}
+}
+
+pub fn openpty(window_size: Option<WindowSize>) -> CResult<Pty> {
+	let master = posix_openpt(O_RDWR | O_NOCTTY | O_CLOEXEC)?;
+	grantpt(&master)?;
+	unlockpt(&master)?;
+	let slave_name = ptsname(&master)?;
+	let slave = open_with_flags(
+		CString::new(slave_name.as_str()).unwrap(),	// Came from a C string
+		O_RDWR | O_NOCTTY | O_CLOEXEC,
+	)?;
+	if let Some(window_size) = window_size {
+		set_window_size(&slave, window_size)?;
+	}
+	Ok(Pty {
+		master,
+		slave,
+		slave_name,
+	})
+}
+
+pub fn set_window_size<Fd: AsFd>(fd: Fd, window_size: WindowSize) -> CResult<()> {
+	let size: libc::winsize = window_size.into();
+	match unsafe { libc::ioctl(fd.as_fd().as_raw_fd(), libc::TIOCSWINSZ, &size as *const libc::winsize) } {
+		-1 => Err(CError::new_from_errno()),
+		_ => Ok(()),
+	}
+}
+
+pub fn get_window_size<Fd: AsFd>(fd: Fd) -> CResult<WindowSize> {
+	let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+	match unsafe { libc::ioctl(fd.as_fd().as_raw_fd(), libc::TIOCGWINSZ, &mut size as *mut libc::winsize) } {
+		-1 => Err(CError::new_from_errno()),
+		_ => Ok(size.into()),
+	}
+}
+
+// Makes fd the controlling terminal of the calling process, which must be a
+// session leader without one
+pub fn make_controlling_terminal<Fd: AsFd>(fd: Fd) -> CResult<()> {
+	match unsafe { libc::ioctl(fd.as_fd().as_raw_fd(), libc::TIOCSCTTY as _, 0) } {
+		-1 => Err(CError::new_from_errno()),
+		_ => Ok(()),
+	}
+}
+
+pub enum ForkPtyResult {
+	Child,
+	Parent {
+		pid: libc::pid_t,
+		master: FileDescriptor,
+	},
+}
+
+// Makes slave the controlling terminal and stdio of a new session
+fn attach_to_slave(slave: &FileDescriptor) -> CResult<()> {
+	if unsafe { libc::setsid() } == -1 {
+		return Err(CError::new_from_errno());
+	}
+	make_controlling_terminal(slave)?;
+	FileDescriptor::wrap_stdin(|fd| fd.redirect_from(slave))?;
+	FileDescriptor::wrap_stdout(|fd| fd.redirect_from(slave))?;
+	FileDescriptor::wrap_stderr(|fd| fd.redirect_from(slave))
+}
+
+// Forks a child whose controlling terminal and stdio are the slave side of a
+// new pty. The parent keeps the master side. If setting up the terminal
+// fails, the child exits with 127 rather than returning
+pub fn fork_pty(window_size: Option<WindowSize>) -> CResult<ForkPtyResult> {
+	let Pty { master, slave, .. } = openpty(window_size)?;
+
+	match fork()? {
+		ForkResult::Child => {
+			drop(master);
+			exit_child_on_error(attach_to_slave(&slave));
+			Ok(ForkPtyResult::Child)
+		},
+		ForkResult::Parent(pid) => Ok(ForkPtyResult::Parent {
+			pid,
+			master,
+		}),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::wait::waitpid;
+
+	#[test]
+	fn fork_pty_child_writes_to_the_master() {
+		match fork_pty(None).unwrap() {
+			ForkPtyResult::Child => unsafe {
+				let is_tty = libc::isatty(1);
+				libc::write(1, b"hello".as_ptr() as *const libc::c_void, 5);
+				libc::_exit(if is_tty == 1 { 0 } else { 1 })
+			},
+			ForkPtyResult::Parent { pid, mut master } => {
+				let mut output = vec![];
+				// Reading the master fails with IO once the slave is closed
+				while let Ok(bytes) = master.read_bytes(64) {
+					if bytes.is_empty() {
+						break;
+					}
+					output.extend_from_slice(&bytes);
+				}
+				assert_eq!(output, b"hello");
+				let result = waitpid(pid).unwrap();
+				assert!(result.status.exited_normally());
+				assert_eq!(result.status.exit_status(), 0);
+			},
+		}
+	}
+
+	#[test]
+	fn failing_terminal_setup_exits_the_child() {
+		// Not a terminal, so it can't become the controlling one
+		let not_a_tty = open_with_flags(CString::new("/dev/null").unwrap(), O_RDWR).unwrap();
+		match fork().unwrap() {
+			ForkResult::Child => {
+				exit_child_on_error(attach_to_slave(&not_a_tty));
+				unsafe { libc::_exit(0) }
+			},
+			ForkResult::Parent(pid) => {
+				let result = waitpid(pid).unwrap();
+				assert!(result.status.exited_normally());
+				assert_eq!(result.status.exit_status(), 127);
+			},
+		}
+	}
+}
@@ -0,0 +1,144 @@
+use std::{convert::Infallible, mem::MaybeUninit};
+
+use crate::{c_error::CError, c_result::CResult, prctl::prctl, wait::{options::WNOHANG, wait, waitpid_with_options, WaitResult}};
+
+// Signals an init process passes on to its main child
+pub static FORWARDED_SIGNALS: &[libc::c_int] = &[
+	libc::SIGHUP,
+	libc::SIGINT,
+	libc::SIGQUIT,
+	libc::SIGTERM,
+	libc::SIGUSR1,
+	libc::SIGUSR2,
+	libc::SIGWINCH,
+	libc::SIGCONT,
+	libc::SIGTSTP,
+	libc::SIGTTIN,
+	libc::SIGTTOU,
+];
+
+pub fn set_child_subreaper(subreaper: bool) -> CResult<()> {
+	prctl(libc::PR_SET_CHILD_SUBREAPER, subreaper as libc::c_ulong).map(|_| ())
+}
+
+pub fn is_child_subreaper() -> CResult<bool> {
+	let mut subreaper: libc::c_int = 0;
+	prctl(libc::PR_GET_CHILD_SUBREAPER, &mut subreaper as *mut libc::c_int as libc::c_ulong)?;
+	Ok(subreaper != 0)
+}
+
+// Collects every child that has already exited, without blocking
+pub fn reap_orphans() -> CResult<Vec<WaitResult>> {
+	let mut reaped = vec![];
+	loop {
+		match waitpid_with_options(-1, WNOHANG) {
+			Ok(result) if result.pid == 0 => break,
+			Ok(result) => reaped.push(result),
+			Err(CError::Child) => break,
+			Err(CError::Interrupted) => continue,
+			Err(err) => return Err(err),
+		}
+	}
+	Ok(reaped)
+}
+
+// Blocks until every child, including reparented descendants, has exited
+pub fn reap_all() -> ReapAll {
+	ReapAll
+}
+
+pub struct ReapAll;
+
+impl Iterator for ReapAll {
+	type Item = CResult<WaitResult>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			match wait() {
+				Ok(result) => return Some(Ok(result)),
+				Err(CError::Child) => return None,
+				Err(CError::Interrupted) => continue,
+				Err(err) => return Some(Err(err)),
+			}
+		}
+	}
+}
+
+fn forwarded_signal_set() -> CResult<libc::sigset_t> {
+	let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+	unsafe {
+		if libc::sigemptyset(set.as_mut_ptr()) == -1 {
+			return Err(CError::new_from_errno());
+		}
+		for signal in FORWARDED_SIGNALS.iter().chain(&[libc::SIGCHLD]) {
+			if libc::sigaddset(set.as_mut_ptr(), *signal) == -1 {
+				return Err(CError::new_from_errno());
+			}
+		}
+		Ok(set.assume_init())
+	}
+}
+
+fn change_init_signal_mask(how: libc::c_int) -> CResult<()> {
+	let set = forwarded_signal_set()?;
+	match unsafe { libc::pthread_sigmask(how, &set, std::ptr::null_mut()) } {
+		0 => Ok(()),
+		errno => Err(CError::from(errno)),
+	}
+}
+
+// Call before forking the main child so no signal is lost before init() starts
+pub fn block_init_signals() -> CResult<()> {
+	change_init_signal_mask(libc::SIG_BLOCK)
+}
+
+// Call in the main child after forking, since the signal mask is inherited
+pub fn unblock_init_signals() -> CResult<()> {
+	change_init_signal_mask(libc::SIG_UNBLOCK)
+}
+
+// Forwards signals to main_pid and reaps orphans until main_pid exits.
+// Orphans are passed to on_orphan as they are reaped.
+pub fn run_as_init<CB: FnMut(WaitResult)>(main_pid: libc::pid_t, mut on_orphan: CB) -> CResult<WaitResult> {
+	block_init_signals()?;
+	let set = forwarded_signal_set()?;
+
+	loop {
+		for result in reap_orphans()? {
+			if result.pid == main_pid {
+				return Ok(result);
+			}
+			on_orphan(result);
+		}
+
+		let mut info = MaybeUninit::<libc::siginfo_t>::uninit();
+		match unsafe { libc::sigwaitinfo(&set, info.as_mut_ptr()) } {
+			-1 => match CError::new_from_errno() {
+				CError::Interrupted => continue,
+				err => return Err(err),
+			},
+			libc::SIGCHLD => {},
+			signal => {
+				if unsafe { libc::kill(main_pid, signal) } == -1 {
+					match CError::new_from_errno() {
+						// The main child already exited and will be reaped above
+						CError::NoSuchProcess => {},
+						err => return Err(err),
+					}
+				}
+			},
+		}
+	}
+}
+
+// Runs as a container init and exits with the main child's status. Only
+// returns if reaping fails, so the caller can report the error
+pub fn init(main_pid: libc::pid_t) -> CResult<Infallible> {
+	let result = run_as_init(main_pid, |_| {})?;
+	if result.status.exited_normally() {
+		std::process::exit(result.status.exit_status() as i32)
+	}
+	else {
+		std::process::exit(128 + result.status.terminating_signal())
+	}
+}
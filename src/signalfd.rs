@@ -0,0 +1,220 @@
+use std::{mem::MaybeUninit, os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd}};
+
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult, file::FileDescriptor};
+
+// Linux's real-time signals stop at 64
+static MAX_SIGNAL: libc::c_int = 64;
+
+#[derive(Clone, Copy)]
+pub struct SignalSet {
+	set: libc::sigset_t,
+}
+
+impl SignalSet {
+	pub fn empty() -> Self {
+		let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+		unsafe { libc::sigemptyset(set.as_mut_ptr()) };
+		Self {
+			set: unsafe { set.assume_init() },
+		}
+	}
+
+	pub fn full() -> Self {
+		let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+		unsafe { libc::sigfillset(set.as_mut_ptr()) };
+		Self {
+			set: unsafe { set.assume_init() },
+		}
+	}
+
+	pub fn from_signals(signals: &[libc::c_int]) -> CResult<Self> {
+		let mut set = Self::empty();
+		for signal in signals {
+			set.add(*signal)?;
+		}
+		Ok(set)
+	}
+
+	pub fn add(&mut self, signal: libc::c_int) -> CResult<()> {
+		match unsafe { libc::sigaddset(&mut self.set, signal) } {
+			0 => Ok(()),
+			_ => Err(CError::new_from_errno()),
+		}
+	}
+
+	pub fn remove(&mut self, signal: libc::c_int) -> CResult<()> {
+		match unsafe { libc::sigdelset(&mut self.set, signal) } {
+			0 => Ok(()),
+			_ => Err(CError::new_from_errno()),
+		}
+	}
+
+	pub fn contains(&self, signal: libc::c_int) -> bool {
+		unsafe { libc::sigismember(&self.set, signal) == 1 }
+	}
+
+	pub fn signals(&self) -> impl Iterator<Item = libc::c_int> + '_ {
+		(1..=MAX_SIGNAL).filter(move |signal| self.contains(*signal))
+	}
+
+	fn change_thread_mask(&self, how: libc::c_int) -> CResult<SignalSet> {
+		let mut previous = SignalSet::empty();
+		match unsafe { libc::pthread_sigmask(how, &self.set, &mut previous.set) } {
+			0 => Ok(previous),
+			errno => Err(CError::from(errno)),
+		}
+	}
+
+	// Blocks the signals in the calling thread, as SignalFd needs, and
+	// returns the previous mask
+	pub fn block(&self) -> CResult<SignalSet> {
+		self.change_thread_mask(libc::SIG_BLOCK)
+	}
+
+	pub fn unblock(&self) -> CResult<SignalSet> {
+		self.change_thread_mask(libc::SIG_UNBLOCK)
+	}
+
+	pub fn set_thread_mask(&self) -> CResult<SignalSet> {
+		self.change_thread_mask(libc::SIG_SETMASK)
+	}
+}
+
+impl std::fmt::Debug for SignalSet {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_set().entries(self.signals()).finish()
+	}
+}
+
+bitflags! {
+	pub struct SignalFdFlags: libc::c_int {
+		const CLOEXEC = libc::SFD_CLOEXEC;
+		const NONBLOCK = libc::SFD_NONBLOCK;
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignalInfo {
+	pub signal: libc::c_int,
+	// SI_USER, SI_QUEUE, CLD_EXITED and so on
+	pub code: libc::c_int,
+	// The sender, or the child for SIGCHLD
+	pub pid: libc::pid_t,
+	pub uid: libc::uid_t,
+	// The exit status or signal of the child for SIGCHLD
+	pub status: libc::c_int,
+	// The value sent with sigqueue
+	pub value: libc::c_int,
+}
+
+impl From<libc::signalfd_siginfo> for SignalInfo {
+	fn from(info: libc::signalfd_siginfo) -> Self {
+		Self {
+			signal: info.ssi_signo as libc::c_int,
+			code: info.ssi_code,
+			pid: info.ssi_pid as libc::pid_t,
+			uid: info.ssi_uid as libc::uid_t,
+			status: info.ssi_status,
+			value: info.ssi_int,
+		}
+	}
+}
+
+// Receives signals by reading a descriptor instead of through handlers; the
+// signals must be blocked first, or they are delivered the usual way
+pub struct SignalFd {
+	fd: FileDescriptor,
+}
+
+impl SignalFd {
+	pub fn new(signals: &SignalSet, flags: SignalFdFlags) -> CResult<Self> {
+		match unsafe { libc::signalfd(-1, &signals.set, flags.bits()) } {
+			-1 => Err(CError::new_from_errno()),
+			fd => Ok(Self {
+				fd: unsafe { FileDescriptor::from_unowned(fd) },
+			}),
+		}
+	}
+
+	pub fn set_signals(&self, signals: &SignalSet) -> CResult<()> {
+		match unsafe { libc::signalfd(self.fd.fd, &signals.set, 0) } {
+			-1 => Err(CError::new_from_errno()),
+			_ => Ok(()),
+		}
+	}
+
+	pub fn fd(&self) -> &FileDescriptor {
+		&self.fd
+	}
+
+	pub fn into_fd(self) -> FileDescriptor {
+		self.fd
+	}
+
+	pub fn set_nonblocking(&mut self, nonblocking: bool) -> CResult<()> {
+		self.fd.set_nonblocking(nonblocking)
+	}
+
+	// Blocks until one of the signals is pending, or fails with Again when
+	// non-blocking
+	pub fn read(&self) -> CResult<SignalInfo> {
+		let mut info = MaybeUninit::<libc::signalfd_siginfo>::uninit();
+		let size = std::mem::size_of::<libc::signalfd_siginfo>();
+		loop {
+			match unsafe { libc::read(self.fd.fd, info.as_mut_ptr() as *mut libc::c_void, size) } {
+				-1 => match CError::new_from_errno() {
+					CError::Interrupted => continue,
+					err => return Err(err),
+				},
+				_ => return Ok(unsafe { info.assume_init() }.into()),
+			}
+		}
+	}
+
+	// Returns None instead of failing with Again, for non-blocking use
+	pub fn try_read(&self) -> CResult<Option<SignalInfo>> {
+		match self.read() {
+			Ok(info) => Ok(Some(info)),
+			Err(CError::Again) | Err(CError::WouldBlock) => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+}
+
+impl AsRawFd for SignalFd {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.fd
+	}
+}
+
+impl AsFd for SignalFd {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.fd.as_fd()
+	}
+}
+
+impl From<SignalFd> for FileDescriptor {
+	fn from(signalfd: SignalFd) -> Self {
+		signalfd.fd
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn a_blocked_signal_is_read_back() {
+		let signals = SignalSet::from_signals(&[libc::SIGUSR2]).unwrap();
+		// raise sends the signal to this thread, so the others are unaffected
+		let previous = signals.block().unwrap();
+		let signal_fd = SignalFd::new(&signals, SignalFdFlags::CLOEXEC).unwrap();
+		assert_eq!(unsafe { libc::raise(libc::SIGUSR2) }, 0);
+		let info = signal_fd.read().unwrap();
+		previous.set_thread_mask().unwrap();
+		assert_eq!(info.signal, libc::SIGUSR2);
+		assert_eq!(info.pid, unsafe { libc::getpid() });
+	}
+}
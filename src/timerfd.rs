@@ -0,0 +1,229 @@
+use std::{os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd}, time::Duration};
+
+use bitflags::bitflags;
+
+use crate::{c_error::CError, c_result::CResult, file::FileDescriptor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+	// Doesn't advance while the system is suspended
+	Monotonic,
+	// Like Monotonic, but includes time spent suspended
+	BootTime,
+}
+
+impl From<Clock> for libc::clockid_t {
+	fn from(clock: Clock) -> Self {
+		match clock {
+			Clock::Monotonic => libc::CLOCK_MONOTONIC,
+			Clock::BootTime => libc::CLOCK_BOOTTIME,
+		}
+	}
+}
+
+impl Clock {
+	// The current time on this clock, for arming timers at an absolute time
+	pub fn now(self) -> CResult<Duration> {
+		let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+		match unsafe { libc::clock_gettime(self.into(), &mut time) } {
+			0 => Ok(Duration::new(time.tv_sec as u64, time.tv_nsec as u32)),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("clock_gettime returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+}
+
+bitflags! {
+	pub struct TimerFdFlags: libc::c_int {
+		const CLOEXEC = libc::TFD_CLOEXEC;
+		const NONBLOCK = libc::TFD_NONBLOCK;
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiration {
+	// Relative to when the timer is armed
+	After(Duration),
+	// A time on the timer's clock, as returned by Clock::now
+	At(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerSetting {
+	// None if the timer is disarmed
+	pub remaining: Option<Duration>,
+	// None for one-shot timers
+	pub interval: Option<Duration>,
+}
+
+fn to_timespec(duration: Duration) -> libc::timespec {
+	libc::timespec {
+		tv_sec: duration.as_secs() as libc::time_t,
+		tv_nsec: duration.subsec_nanos() as libc::c_long,
+	}
+}
+
+fn from_timespec(time: libc::timespec) -> Option<Duration> {
+	match (time.tv_sec, time.tv_nsec) {
+		(0, 0) => None,
+		(seconds, nanoseconds) => Some(Duration::new(seconds as u64, nanoseconds as u32)),
+	}
+}
+
+// A timer whose descriptor becomes readable when it expires
+pub struct TimerFd {
+	fd: FileDescriptor,
+	clock: Clock,
+}
+
+impl TimerFd {
+	pub fn new(clock: Clock, flags: TimerFdFlags) -> CResult<Self> {
+		match unsafe { libc::timerfd_create(clock.into(), flags.bits()) } {
+			-1 => Err(CError::new_from_errno()),
+			fd => Ok(Self {
+				fd: unsafe { FileDescriptor::from_unowned(fd) },
+				clock,
+			}),
+		}
+	}
+
+	pub fn clock(&self) -> Clock {
+		self.clock
+	}
+
+	pub fn fd(&self) -> &FileDescriptor {
+		&self.fd
+	}
+
+	pub fn into_fd(self) -> FileDescriptor {
+		self.fd
+	}
+
+	pub fn set_nonblocking(&mut self, nonblocking: bool) -> CResult<()> {
+		self.fd.set_nonblocking(nonblocking)
+	}
+
+	fn set(&self, expiration: Expiration, interval: Duration) -> CResult<()> {
+		let (value, flags) = match expiration {
+			Expiration::After(after) => (after, 0),
+			Expiration::At(at) => (at, libc::TFD_TIMER_ABSTIME),
+		};
+		// A value of zero would disarm the timer instead of firing it now
+		let value = value.max(Duration::from_nanos(1));
+		let setting = libc::itimerspec {
+			it_value: to_timespec(value),
+			it_interval: to_timespec(interval),
+		};
+		match unsafe { libc::timerfd_settime(self.fd.fd, flags, &setting, std::ptr::null_mut()) } {
+			0 => Ok(()),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("timerfd_settime returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+
+	pub fn set_one_shot(&self, expiration: Expiration) -> CResult<()> {
+		self.set(expiration, Duration::ZERO)
+	}
+
+	// First expires at first, then every interval after that
+	pub fn set_interval(&self, first: Expiration, interval: Duration) -> CResult<()> {
+		if interval == Duration::ZERO {
+			return Err(CError::Invalid);
+		}
+		self.set(first, interval)
+	}
+
+	pub fn disarm(&self) -> CResult<()> {
+		let setting = libc::itimerspec {
+			it_value: to_timespec(Duration::ZERO),
+			it_interval: to_timespec(Duration::ZERO),
+		};
+		match unsafe { libc::timerfd_settime(self.fd.fd, 0, &setting, std::ptr::null_mut()) } {
+			0 => Ok(()),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("timerfd_settime returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+
+	pub fn get(&self) -> CResult<TimerSetting> {
+		let mut setting: libc::itimerspec = unsafe { std::mem::zeroed() };
+		match unsafe { libc::timerfd_gettime(self.fd.fd, &mut setting) } {
+			0 => Ok(TimerSetting {
+				remaining: from_timespec(setting.it_value),
+				interval: from_timespec(setting.it_interval),
+			}),
+			-1 => Err(CError::new_from_errno()),
+			bad_return => panic!("timerfd_gettime returned {}, which is different from 0 or -1", bad_return),
+		}
+	}
+
+	// Blocks until the timer expires, or fails with Again when non-blocking;
+	// returns how many times it expired since the last wait
+	pub fn wait(&self) -> CResult<u64> {
+		let mut bytes = [0u8; 8];
+		loop {
+			match unsafe { libc::read(self.fd.fd, bytes.as_mut_ptr() as *mut libc::c_void, bytes.len()) } {
+				-1 => match CError::new_from_errno() {
+					CError::Interrupted => continue,
+					err => return Err(err),
+				},
+				_ => return Ok(u64::from_ne_bytes(bytes)),
+			}
+		}
+	}
+
+	// Returns None instead of failing with Again, for non-blocking use
+	pub fn try_wait(&self) -> CResult<Option<u64>> {
+		match self.wait() {
+			Ok(expirations) => Ok(Some(expirations)),
+			Err(CError::Again) | Err(CError::WouldBlock) => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+}
+
+impl AsRawFd for TimerFd {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.fd
+	}
+}
+
+impl AsFd for TimerFd {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.fd.as_fd()
+	}
+}
+
+impl From<TimerFd> for FileDescriptor {
+	fn from(timerfd: TimerFd) -> Self {
+		timerfd.fd
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn a_one_shot_timer_fires_once_and_then_reads_as_disarmed() {
+		let timer = TimerFd::new(Clock::Monotonic, TimerFdFlags::CLOEXEC).unwrap();
+		assert_eq!(timer.get().unwrap().remaining, None);
+		timer.set_one_shot(Expiration::After(Duration::from_millis(10))).unwrap();
+		let setting = timer.get().unwrap();
+		assert!(setting.remaining.is_some());
+		assert_eq!(setting.interval, None);
+		assert_eq!(timer.wait().unwrap(), 1);
+		assert_eq!(timer.get().unwrap().remaining, None);
+	}
+
+	#[test]
+	fn disarming_stops_a_timer() {
+		let mut timer = TimerFd::new(Clock::Monotonic, TimerFdFlags::CLOEXEC).unwrap();
+		timer.set_nonblocking(true).unwrap();
+		timer.set_interval(Expiration::After(Duration::from_secs(60)), Duration::from_secs(60)).unwrap();
+		assert_eq!(timer.get().unwrap().interval, Some(Duration::from_secs(60)));
+		timer.disarm().unwrap();
+		assert_eq!(timer.get().unwrap(), TimerSetting { remaining: None, interval: None });
+		assert_eq!(timer.try_wait().unwrap(), None);
+	}
+}